@@ -0,0 +1,10 @@
+//! Parse the result of Hello
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::protocol::{Payload, Capabilities};
+
+
+fuzz_target!(|data : &[u8]| {
+    let _ = Capabilities::decode(data);
+});
@@ -0,0 +1,65 @@
+//! Push a command through the dispatch of the EM, as read from an in-memory stream
+#![no_main]
+#[macro_use] extern crate lazy_static;
+
+use std::io::{self, prelude::*};
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::{Config, EventManager};
+use event_manager::protocol::Stream;
+
+// commands that make the EM start processes, connect to modules or other EMs, or keep
+// state that Reset does not clear. Their payloads are covered by the other targets
+const EXCLUDED : &[u8] = &[
+    1,  // CallEntrypoint
+    2,  // RemoteOutput
+    3,  // LoadSM
+    7,  // RemoteRequest
+    64, // LoadModule
+    67, // UploadBegin
+    71, // InstantiateSM
+    73, // RestartSM
+    78  // RemoteOutputAck
+];
+
+const RESET : u8 = 4;
+
+lazy_static! {
+    static ref EM : EventManager = EventManager::bind(Config::new(0)).unwrap();
+}
+
+
+/// Reads the input, discards what the EM writes
+struct MemoryStream<'a> {
+    input : &'a [u8]
+}
+
+impl<'a> Read for MemoryStream<'a> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<'a> Write for MemoryStream<'a> {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Stream for MemoryStream<'a> {}
+
+
+fuzz_target!(|data : &[u8]| {
+    if data.is_empty() || EXCLUDED.contains(&data[0]) {
+        return;
+    }
+
+    EM.handle(&mut MemoryStream { input : data });
+
+    // start from a clean state, e.g. without connections added by a Batch
+    EM.handle(&mut MemoryStream { input : &[RESET] });
+});
@@ -0,0 +1,16 @@
+//! Parse the files of SGX modules and enclave policies
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::EnclavePolicy;
+use event_manager::fuzzing::{SigStruct, compute_mrenclave};
+
+
+fuzz_target!(|data : &[u8]| {
+    let _ = SigStruct::parse(data);
+    let _ = compute_mrenclave(&mut &data[..]);
+
+    if let Ok(policy) = std::str::from_utf8(data) {
+        let _ = EnclavePolicy::parse(policy);
+    }
+});
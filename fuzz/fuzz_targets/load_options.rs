@@ -0,0 +1,12 @@
+//! Parse the options of LoadModule and InstantiateSM
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::fuzzing::parse_tlv;
+use event_manager::protocol::{Payload, LoadOptions};
+
+
+fuzz_target!(|data : &[u8]| {
+    let _ = parse_tlv(data);
+    let _ = LoadOptions::decode(data);
+});
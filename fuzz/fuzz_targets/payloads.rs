@@ -0,0 +1,50 @@
+//! Decode the payloads of the protocol, and check that valid ones survive a round trip
+#![no_main]
+
+use std::fmt::Debug;
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::protocol::*;
+
+
+fn check<P : Payload + PartialEq + Debug>(data : &[u8]) {
+    if let Ok(payload) = P::decode(data) {
+        assert_eq!(P::decode(&payload.encode()), Ok(payload));
+    }
+}
+
+
+fuzz_target!(|data : &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    // first byte selects the payload
+    let payload = &data[1..];
+    match data[0] % 22 {
+        0   => check::<AddConnection>(payload),
+        1   => check::<CallEntrypoint>(payload),
+        2   => check::<LoadSM>(payload),
+        3   => check::<LoadSMResult>(payload),
+        4   => check::<Reset>(payload),
+        5   => check::<RegisterEntrypoint>(payload),
+        6   => check::<ModuleOutput>(payload),
+        7   => check::<RemoteOutput>(payload),
+        8   => check::<DeadLetters>(payload),
+        9   => check::<SetConnectionPolicy>(payload),
+        10  => check::<SetConnectionOrdering>(payload),
+        11  => check::<QueueStats>(payload),
+        12  => check::<SetConnectionPriority>(payload),
+        13  => check::<LoadOptions>(payload),
+        14  => check::<InstantiateSM>(payload),
+        15  => check::<RegisterModule>(payload),
+        16  => check::<Capabilities>(payload),
+        17  => check::<RemoveArtifacts>(payload),
+        18  => check::<UploadBegin>(payload),
+        19  => check::<UploadData>(payload),
+        20  => check::<GetModuleLogs>(payload),
+        _   => check::<Batch>(payload)
+    }
+
+    let _ = read_result_from(&mut &data[..]);
+});
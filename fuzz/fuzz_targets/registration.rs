@@ -0,0 +1,18 @@
+//! Parse the payload of RegisterModule
+#![no_main]
+#[macro_use] extern crate lazy_static;
+
+use libfuzzer_sys::fuzz_target;
+use event_manager::{Config, EventManager};
+use event_manager::fuzzing::Registration;
+
+lazy_static! {
+    static ref EM : EventManager = EventManager::bind(Config::new(0)).unwrap();
+}
+
+
+fuzz_target!(|data : &[u8]| {
+    if let Ok((_, registration)) = Registration::parse(&EM, data) {
+        assert!(registration.metadata.iter().all(|(k, _)| !k.contains('=')));
+    }
+});
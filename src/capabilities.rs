@@ -0,0 +1,109 @@
+use std::net::{TcpStream, SocketAddrV4};
+use std::time::Duration;
+
+use reactive_net::{CommandCode, Error};
+use log::{debug, warn};
+
+use crate::commands::ExtCommandCode;
+use crate::protocol::{Payload, write_raw_command, read_raw_result};
+use crate::EventManager;
+
+pub use crate::protocol::Capabilities;
+
+
+/// Version of the protocol spoken by this EM. EMs that do not know Hello are version 0
+pub const PROTOCOL_VERSION : u16 = 1;
+
+const FEATURES : &[&str] = &[
+    "transport-tcp",
+    "transport-unix",
+    "upload-chunked",
+    "compression-gzip",
+    "compression-zstd",
+    "load-by-hash",
+    "module-logs",
+    // commands can ask for all result codes with `ERROR_CODES_FLAG`
+    "error-codes",
+    "priorities"
+];
+
+const HELLO_TIMEOUT : Duration = Duration::from_secs(5);
+
+
+impl Capabilities {
+    /// Capabilities of this EM
+    pub fn local(em : &EventManager) -> Capabilities {
+        let mut commands = base_commands();
+        commands.extend((0..=u8::MAX).filter(|c| ExtCommandCode::from_u8(*c).is_some()));
+
+        let mut loaders : Vec<String> = em.loaders.keys().map(|l| l.to_string()).collect();
+        loaders.sort();
+
+        Capabilities {
+            version : PROTOCOL_VERSION,
+            commands,
+            loaders,
+            features : FEATURES.iter().map(|f| f.to_string()).collect()
+        }
+    }
+
+    /// Capabilities assumed for EMs that do not support Hello
+    pub fn legacy() -> Capabilities {
+        Capabilities {
+            version : 0,
+            commands : base_commands(),
+            loaders : Vec::new(),
+            features : vec!["transport-tcp".to_string()]
+        }
+    }
+
+    pub fn supports(&self, code : u8) -> bool {
+        self.commands.contains(&code)
+    }
+}
+
+
+fn base_commands() -> Vec<u8> {
+    (0..=u8::MAX).filter(|c| CommandCode::from_u8(*c).is_some()).collect()
+}
+
+
+/// Capabilities of a remote EM, asking them with Hello the first time
+pub fn peer_capabilities(em : &EventManager, addr : SocketAddrV4) -> Result<Capabilities, Error> {
+    if let Some(caps) = em.peers.lock().unwrap().get(&addr) {
+        return Ok(caps.clone());
+    }
+
+    let caps = hello(addr)?;
+    debug!("EM {} has protocol version {}", addr, caps.version);
+
+    em.peers.lock().unwrap().insert(addr, caps.clone());
+    Ok(caps)
+}
+
+
+/// Forget the capabilities of a remote EM, e.g. because it could have been restarted
+pub fn forget_peer(em : &EventManager, addr : SocketAddrV4) {
+    em.peers.lock().unwrap().remove(&addr);
+}
+
+
+fn hello(addr : SocketAddrV4) -> Result<Capabilities, Error> {
+    let mut stream = match TcpStream::connect(addr) {
+        Ok(s) => s,
+        Err(_) => return Err(Error::NetworkError)
+    };
+    let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+
+    write_raw_command(&mut stream, ExtCommandCode::Hello as u8, &PROTOCOL_VERSION.to_be_bytes())
+        .map_err(|_| Error::NetworkError)?;
+
+    match read_raw_result(&mut stream).map_err(|_| Error::NetworkError)? {
+        (0, payload)    => Capabilities::decode(&payload).map_err(|e| {
+            warn!("Invalid capabilities from {}: {}", addr, e);
+            Error::InvalidPayload
+        }),
+        // older EMs reply IllegalCommand
+        _               => Ok(Capabilities::legacy())
+    }
+}
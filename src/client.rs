@@ -0,0 +1,258 @@
+use std::io::{self, prelude::*};
+use std::net::{TcpStream, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use reactive_net::CommandCode;
+
+use crate::protocol::*;
+use crate::commands::{ExtCommandCode, ERROR_CODES_FLAG};
+use crate::capabilities::{Capabilities, PROTOCOL_VERSION};
+
+
+pub enum ClientError {
+    /// the EM could not be reached, or the connection was lost
+    Io(io::Error),
+    /// the EM did not answer in time
+    Timeout,
+    /// the EM answered with an error code (and possibly a payload)
+    Failed(ResultCode, Vec<u8>),
+    /// the EM answered with something that is not valid
+    Invalid(String)
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e)              => write!(f, "{}", e),
+            ClientError::Timeout            => write!(f, "Timeout"),
+            ClientError::Failed(code, _)    => write!(f, "Command failed: {:?}", code),
+            ClientError::Invalid(msg)       => write!(f, "Invalid response: {}", msg)
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e : io::Error) -> ClientError {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ClientError::Timeout,
+            _                                                   => ClientError::Io(e)
+        }
+    }
+}
+
+impl From<DecodeError> for ClientError {
+    fn from(e : DecodeError) -> ClientError {
+        ClientError::Invalid(e.to_string())
+    }
+}
+
+
+/// Client of an event manager
+///
+/// The EM handles one command per connection, so each call opens a new one
+#[derive(Clone, Debug)]
+pub struct Client {
+    address : SocketAddr,
+    timeout : Option<Duration>,
+    error_codes : bool
+}
+
+impl Client {
+    pub fn new(address : SocketAddr) -> Client {
+        Client {
+            address,
+            timeout : None,
+            error_codes : true
+        }
+    }
+
+    /// Timeout for connecting, sending a command and waiting for its result
+    pub fn set_timeout(&mut self, timeout : Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Whether the commands ask for all result codes (the default), instead of the codes of
+    /// `reactive_net` only
+    pub fn set_error_codes(&mut self, error_codes : bool) {
+        self.error_codes = error_codes;
+    }
+
+    pub fn get_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Load a module, returns its index
+    pub fn load_sm(&self, files : Vec<Vec<u8>>) -> Result<u16, ClientError> {
+        let mut stream = self.connect()?;
+
+        // files are not preceded by the length of the payload
+        let mut buf = vec![CommandCode::LoadSM as u8];
+        buf.extend_from_slice(&LoadSM { files }.encode());
+        stream.write_all(&buf)?;
+
+        let result = read_result(&mut stream)?;
+        Ok(LoadSMResult::decode(&result)?.index)
+    }
+
+    pub fn add_connection(&self, conn_id : u16, to_sm : u16, em : SocketAddrV4, local : bool)
+            -> Result<(), ClientError> {
+        let conn = AddConnection {
+            conn_id,
+            to_sm,
+            local,
+            em_port : em.port(),
+            em_address : *em.ip()
+        };
+
+        self.command(CommandCode::AddConnection as u8, &conn.encode()).map(|_| ())
+    }
+
+    /// Remove a destination added with `add_connection`
+    pub fn remove_connection(&self, conn_id : u16, to_sm : u16, em : SocketAddrV4, local : bool)
+            -> Result<(), ClientError> {
+        let conn = RemoveConnection {
+            conn_id,
+            to_sm,
+            local,
+            em_port : em.port(),
+            em_address : *em.ip()
+        };
+
+        self.command(ExtCommandCode::RemoveConnection as u8, &conn.encode()).map(|_| ())
+    }
+
+    /// Choose how the outputs of a connection are delivered to its destinations
+    pub fn set_connection_policy(&self, conn_id : u16, policy : DeliveryPolicy)
+            -> Result<(), ClientError> {
+        let policy = SetConnectionPolicy { conn_id, policy };
+        self.command(ExtCommandCode::SetConnectionPolicy as u8, &policy.encode()).map(|_| ())
+    }
+
+    /// Choose whether the outputs of a connection are delivered in order
+    pub fn set_connection_ordering(&self, conn_id : u16, ordering : ConnectionOrdering)
+            -> Result<(), ClientError> {
+        let ordering = SetConnectionOrdering { conn_id, ordering };
+        self.command(ExtCommandCode::SetConnectionOrdering as u8, &ordering.encode()).map(|_| ())
+    }
+
+    /// Set the priority of the events of a connection, sent or received by the EM
+    pub fn set_connection_priority(&self, conn_id : u16, priority : Priority)
+            -> Result<(), ClientError> {
+        let priority = SetConnectionPriority { conn_id, priority };
+        self.command(ExtCommandCode::SetConnectionPriority as u8, &priority.encode()).map(|_| ())
+    }
+
+    /// Call an entry point of a module, returns the payload of its result
+    pub fn call_entrypoint(&self, module : u16, entry : u16, data : &[u8])
+            -> Result<Vec<u8>, ClientError> {
+        let call = CallEntrypoint { module, entry, data : data.to_vec() };
+        self.command(CommandCode::CallEntrypoint as u8, &call.encode())
+    }
+
+    pub fn register_entrypoint(&self, module : u16, entry : u16, frequency : u32,
+            priority : Priority) -> Result<(), ClientError> {
+        let task = RegisterEntrypoint { module, entry, frequency, priority };
+        self.command(CommandCode::RegisterEntrypoint as u8, &task.encode()).map(|_| ())
+    }
+
+    pub fn reset(&self) -> Result<(), ClientError> {
+        self.command(CommandCode::Reset as u8, &Reset.encode()).map(|_| ())
+    }
+
+    /// Deliver an output to a module, as another EM would do. There is no result
+    pub fn remote_output(&self, module : u16, conn_id : u16, data : &[u8])
+            -> Result<(), ClientError> {
+        let output = RemoteOutput { module, conn_id, data : data.to_vec() };
+
+        let mut stream = self.connect()?;
+        Ok(write_raw_command(&mut stream, CommandCode::RemoteOutput as u8, &output.encode())?)
+    }
+
+    /// Deliver a request to a module, as another EM would do, returns the response
+    pub fn remote_request(&self, module : u16, conn_id : u16, data : &[u8])
+            -> Result<Vec<u8>, ClientError> {
+        let request = RemoteRequest { module, conn_id, data : data.to_vec() };
+        self.command(CommandCode::RemoteRequest as u8, &request.encode())
+    }
+
+    /// Same as `remote_output`, but the result tells whether the output was delivered.
+    /// Returns the payload of the result of the module
+    pub fn remote_output_ack(&self, module : u16, conn_id : u16, data : &[u8])
+            -> Result<Vec<u8>, ClientError> {
+        let output = RemoteOutput { module, conn_id, data : data.to_vec() };
+        self.command(ExtCommandCode::RemoteOutputAck as u8, &output.encode())
+    }
+
+    /// Outputs that the EM could not deliver. If `clear` is set, they are forgotten
+    pub fn dead_letters(&self, clear : bool) -> Result<DeadLetters, ClientError> {
+        let result = self.command(ExtCommandCode::GetDeadLetters as u8, &[clear as u8])?;
+        Ok(DeadLetters::decode(&result)?)
+    }
+
+    /// Depth and counters of the queues of the modules. If `reset` is set, the counters are
+    /// reset
+    pub fn queue_stats(&self, reset : bool) -> Result<QueueStats, ClientError> {
+        let result = self.command(ExtCommandCode::GetQueueStats as u8, &[reset as u8])?;
+        Ok(QueueStats::decode(&result)?)
+    }
+
+    /// Remove an artifact from the store or, without `hash`, all the artifacts that no
+    /// loaded module uses. Returns the number of artifacts removed
+    pub fn remove_artifacts(&self, hash : Option<&[u8; 32]>) -> Result<u32, ClientError> {
+        let remove = RemoveArtifacts { hash : hash.copied() };
+        let result = self.command(ExtCommandCode::RemoveArtifacts as u8, &remove.encode())?;
+
+        match result.len() {
+            4   => Ok(bytes_to_u32(&result)),
+            n   => Err(DecodeError::Length(n).into())
+        }
+    }
+
+    /// Capabilities of the EM
+    pub fn hello(&self) -> Result<Capabilities, ClientError> {
+        let result = self.command(ExtCommandCode::Hello as u8, &PROTOCOL_VERSION.to_be_bytes())?;
+        Ok(Capabilities::decode(&result)?)
+    }
+
+    /// Send any command, returns the payload of its result
+    pub fn command(&self, code : u8, payload : &[u8]) -> Result<Vec<u8>, ClientError> {
+        let code = match self.error_codes {
+            true    => code | ERROR_CODES_FLAG,
+            false   => code
+        };
+        let mut stream = self.connect()?;
+
+        write_raw_command(&mut stream, code, payload)?;
+        read_result(&mut stream)
+    }
+
+    fn connect(&self) -> Result<TcpStream, ClientError> {
+        let stream = match self.timeout {
+            Some(t) => TcpStream::connect_timeout(&self.address, t)?,
+            None    => TcpStream::connect(self.address)?
+        };
+
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+
+        Ok(stream)
+    }
+}
+
+
+
+fn read_result(stream : &mut TcpStream) -> Result<Vec<u8>, ClientError> {
+    let (code, payload) = read_raw_result(stream)?;
+
+    match ResultCode::from_u8(code) {
+        Some(ResultCode::Ok)    => Ok(payload),
+        Some(code)              => Err(ClientError::Failed(code, payload)),
+        None                    => Err(ClientError::Invalid(format!("Unknown result code: {}", code)))
+    }
+}
@@ -0,0 +1,89 @@
+use reactive_net::CommandCode;
+
+
+/// Set in the code of a command by callers that understand the result codes after
+/// `GenericError`. The result of the other commands only carries the codes of `reactive_net`
+pub const ERROR_CODES_FLAG : u8 = 0x80;
+
+/// Commands handled by this EM in addition to the ones defined in `reactive_net`
+///
+/// Codes start at 64 to leave room for new `reactive_net::CommandCode`s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtCommandCode {
+    LoadModule = 64,
+    GetModuleLogs = 65,
+    UploadArtifact = 66,
+    UploadBegin = 67,
+    UploadData = 68,
+    UploadStatus = 69,
+    UploadFinish = 70,
+    InstantiateSM = 71,
+    UnloadSM = 72,
+    RestartSM = 73,
+    RegisterModule = 74,
+    Hello = 75,
+    Batch = 76,
+    /// same as ModuleOutput / RemoteOutput, but the result tells whether the output was
+    /// delivered
+    ModuleOutputAck = 77,
+    RemoteOutputAck = 78,
+    GetDeadLetters = 79,
+    RemoveConnection = 80,
+    SetConnectionPolicy = 81,
+    SetConnectionOrdering = 82,
+    GetQueueStats = 83,
+    SetConnectionPriority = 84,
+    RemoveArtifacts = 85
+}
+
+impl ExtCommandCode {
+    pub fn from_u8(code : u8) -> Option<ExtCommandCode> {
+        match code {
+            64  => Some(ExtCommandCode::LoadModule),
+            65  => Some(ExtCommandCode::GetModuleLogs),
+            66  => Some(ExtCommandCode::UploadArtifact),
+            67  => Some(ExtCommandCode::UploadBegin),
+            68  => Some(ExtCommandCode::UploadData),
+            69  => Some(ExtCommandCode::UploadStatus),
+            70  => Some(ExtCommandCode::UploadFinish),
+            71  => Some(ExtCommandCode::InstantiateSM),
+            72  => Some(ExtCommandCode::UnloadSM),
+            73  => Some(ExtCommandCode::RestartSM),
+            74  => Some(ExtCommandCode::RegisterModule),
+            75  => Some(ExtCommandCode::Hello),
+            76  => Some(ExtCommandCode::Batch),
+            77  => Some(ExtCommandCode::ModuleOutputAck),
+            78  => Some(ExtCommandCode::RemoteOutputAck),
+            79  => Some(ExtCommandCode::GetDeadLetters),
+            80  => Some(ExtCommandCode::RemoveConnection),
+            81  => Some(ExtCommandCode::SetConnectionPolicy),
+            82  => Some(ExtCommandCode::SetConnectionOrdering),
+            83  => Some(ExtCommandCode::GetQueueStats),
+            84  => Some(ExtCommandCode::SetConnectionPriority),
+            85  => Some(ExtCommandCode::RemoveArtifacts),
+            _   => None
+        }
+    }
+}
+
+
+/// Whether a command manages the EM, instead of carrying an event. Those are served by
+/// their own workers, so that they are not delayed by the events
+pub fn is_control_command(code : u8) -> bool {
+    !matches!((CommandCode::from_u8(code), ExtCommandCode::from_u8(code)),
+        (Some(CommandCode::CallEntrypoint), _) | (Some(CommandCode::RemoteOutput), _) |
+        (Some(CommandCode::ModuleOutput), _) | (Some(CommandCode::RemoteRequest), _) |
+        (_, Some(ExtCommandCode::ModuleOutputAck)) | (_, Some(ExtCommandCode::RemoteOutputAck)))
+}
+
+
+/// Whether a control command can take long, e.g. because it transfers files or waits for a
+/// module. Those have their own workers, so that Reset and the short commands are not
+/// delayed by them
+pub fn is_long_command(code : u8) -> bool {
+    matches!((CommandCode::from_u8(code), ExtCommandCode::from_u8(code)),
+        (Some(CommandCode::LoadSM), _) | (_, Some(ExtCommandCode::LoadModule)) |
+        (_, Some(ExtCommandCode::InstantiateSM)) | (_, Some(ExtCommandCode::RestartSM)) |
+        (_, Some(ExtCommandCode::GetModuleLogs)) | (_, Some(ExtCommandCode::UploadArtifact)) |
+        (_, Some(ExtCommandCode::UploadData)) | (_, Some(ExtCommandCode::UploadFinish)))
+}
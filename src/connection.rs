@@ -1,6 +1,17 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-#[derive(Clone)]
+use crate::protocol::{DeliveryPolicy, ConnectionOrdering};
+
+
+/// How long a replica that could not be reached is skipped
+const RETRY_DOWN_AFTER : Duration = Duration::from_secs(5);
+
+
+/// A destination of a connection
+#[derive(Clone, Debug, PartialEq)]
 pub struct Connection {
     to_sm : u16,
     address : SocketAddrV4,
@@ -21,10 +32,129 @@ impl Connection {
     }
 
     pub fn get_address(&self) -> SocketAddrV4 {
-        self.address.clone()
+        self.address
     }
 
     pub fn is_local_connection(&self) -> bool {
         self.local
     }
 }
+
+
+/// Health of a destination, shared by all the copies of its connection
+#[derive(Default)]
+struct Health {
+    /// outputs being delivered
+    outstanding : AtomicUsize,
+    /// set when the destination could not be reached
+    down_until : Mutex<Option<Instant>>
+}
+
+/// A destination, with its health
+#[derive(Clone)]
+pub struct Destination {
+    pub conn : Connection,
+    health : Arc<Health>
+}
+
+impl Destination {
+    fn is_up(&self) -> bool {
+        match *self.health.down_until.lock().unwrap() {
+            Some(t) => Instant::now() >= t,
+            None    => true
+        }
+    }
+
+    /// Record the start of a delivery, until the guard is dropped
+    pub fn start_delivery(&self) -> Outstanding {
+        self.health.outstanding.fetch_add(1, Ordering::SeqCst);
+        Outstanding { health : self.health.clone() }
+    }
+
+    /// Skip the destination for a while
+    pub fn mark_down(&self) {
+        *self.health.down_until.lock().unwrap() = Some(Instant::now() + RETRY_DOWN_AFTER);
+    }
+
+    pub fn mark_up(&self) {
+        *self.health.down_until.lock().unwrap() = None;
+    }
+}
+
+/// A delivery in progress
+pub struct Outstanding {
+    health : Arc<Health>
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.health.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Destinations of a connection
+///
+/// With `DeliveryPolicy::FanOut`, an output goes to all of them. Otherwise they are
+/// equivalent replicas and an output goes to one of them, the others being tried in turn if
+/// it cannot be reached. With `ConnectionOrdering::Fifo`, outputs are delivered one at a
+/// time
+#[derive(Clone, Default)]
+pub struct Destinations {
+    pub policy : DeliveryPolicy,
+    pub ordering : ConnectionOrdering,
+    list : Vec<Destination>,
+    /// next replica for round-robin
+    next : Arc<AtomicUsize>
+}
+
+impl Destinations {
+    /// Returns false if `conn` is already a destination
+    pub fn add(&mut self, conn : Connection) -> bool {
+        if self.list.iter().any(|d| d.conn == conn) {
+            return false;
+        }
+
+        self.list.push(Destination { conn, health : Arc::new(Health::default()) });
+        true
+    }
+
+    /// Returns false if `conn` is not a destination
+    pub fn remove(&mut self, conn : &Connection) -> bool {
+        let len = self.list.len();
+        self.list.retain(|d| d.conn != *conn);
+        self.list.len() != len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn all(&self) -> &[Destination] {
+        &self.list
+    }
+
+    /// Replicas in the order they should be tried, according to the policy. Replicas that
+    /// are down come last
+    pub fn candidates(&self) -> Vec<Destination> {
+        let mut list = self.list.clone();
+
+        match self.policy {
+            DeliveryPolicy::FanOut | DeliveryPolicy::PrimaryBackup  => (),
+            DeliveryPolicy::RoundRobin                              => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst) % list.len().max(1);
+                list.rotate_left(start);
+            },
+            DeliveryPolicy::LeastOutstanding                        => {
+                // ties are broken in round-robin order
+                let start = self.next.fetch_add(1, Ordering::SeqCst) % list.len().max(1);
+                list.rotate_left(start);
+                list.sort_by_key(|d| d.health.outstanding.load(Ordering::SeqCst));
+            }
+        }
+
+        // stable, so that the order of the policy is kept among replicas that are up
+        list.sort_by_key(|d| !d.is_up());
+        list
+    }
+}
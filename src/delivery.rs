@@ -0,0 +1,103 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::protocol::{CommandError, DeadLetter, DeadLetters};
+use crate::EventManager;
+
+
+// longer descriptions are cut, so that many events fit in the result of GetDeadLetters
+const MAX_MESSAGE_LEN : usize = 256;
+
+
+/// Outputs that could not be delivered: a counter per connection, and the most recent ones
+pub struct DeadLetterLog {
+    undelivered : HashMap<u16, u64>,
+    events : VecDeque<DeadLetter>,
+    /// number of events kept
+    capacity : usize
+}
+
+impl DeadLetterLog {
+    pub fn new(capacity : usize) -> DeadLetterLog {
+        DeadLetterLog {
+            undelivered : HashMap::new(),
+            events : VecDeque::new(),
+            capacity
+        }
+    }
+
+    fn clear(&mut self) {
+        self.undelivered.clear();
+        self.events.clear();
+    }
+}
+
+
+/// Record that an output of connection `conn_id`, for `entry`, was dropped because of `e`
+pub fn record(em : &EventManager, conn_id : u16, entry : u16, e : &CommandError) {
+    record_to(&em.dead_letters, conn_id, entry, e);
+}
+
+
+/// Same as `record`, for threads that do not have the EM
+pub fn record_to(log : &Mutex<DeadLetterLog>, conn_id : u16, entry : u16, e : &CommandError) {
+    warn!("Output of connection {} (entry {}) not delivered: {}", conn_id, entry, e);
+
+    let mut message = e.to_string();
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let mut log = log.lock().unwrap();
+    *log.undelivered.entry(conn_id).or_insert(0) += 1;
+
+    if log.capacity == 0 {
+        return;
+    }
+    if log.events.len() >= log.capacity {
+        log.events.pop_front();
+    }
+    log.events.push_back(DeadLetter { time, conn_id, entry, code : e.code(), message });
+}
+
+
+/// Counters and most recent events, as many as fit in a message. If `clear` is set, they
+/// are reset afterwards
+pub fn report(em : &EventManager, clear : bool) -> DeadLetters {
+    let mut log = em.dead_letters.lock().unwrap();
+
+    let mut undelivered : Vec<(u16, u64)> = log.undelivered.iter()
+        .map(|(c, n)| (*c, *n)).collect();
+    undelivered.sort();
+
+    // counters are 10 bytes, plus the 3-byte header of their entry
+    let mut len = undelivered.len() * 13;
+    let mut events = Vec::new();
+    for event in log.events.iter().rev() {
+        len += event.encoded_len();
+        if len > u16::MAX as usize {
+            break;
+        }
+        events.push(event.clone());
+    }
+
+    if clear {
+        log.clear();
+    }
+
+    DeadLetters { undelivered, events }
+}
+
+
+pub fn clear(em : &EventManager) {
+    em.dead_letters.lock().unwrap().clear();
+}
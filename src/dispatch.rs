@@ -0,0 +1,623 @@
+use std::collections::{BinaryHeap, BTreeMap, HashMap};
+use std::cmp::Ordering;
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use reactive_net::CommandCode;
+
+use crate::commands::{ExtCommandCode, ERROR_CODES_FLAG, is_control_command, is_long_command};
+use crate::protocol::{Priority, ConnectionOrdering, Stream, bytes_to_u16};
+use crate::{Config, EventManager};
+
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Job {
+    priority : Priority,
+    seq : u64,
+    run : Task
+}
+
+// most urgent first, then in arrival order
+impl Ord for Job {
+    fn cmp(&self, other : &Job) -> Ordering {
+        self.priority.cmp(&other.priority).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other : &Job) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other : &Job) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+
+#[derive(Default)]
+struct Jobs {
+    heap : BinaryHeap<Job>,
+    next_seq : u64
+}
+
+/// Workers that run jobs by priority
+pub struct Lane {
+    jobs : Mutex<Jobs>,
+    ready : Condvar
+}
+
+impl Lane {
+    /// Start a lane with `threads` workers
+    pub fn new(threads : usize) -> Arc<Lane> {
+        let lane = Arc::new(Lane {
+            jobs : Mutex::new(Jobs::default()),
+            ready : Condvar::new()
+        });
+
+        for _ in 0..threads.max(1) {
+            let lane = lane.clone();
+            thread::spawn(move || lane.work());
+        }
+
+        lane
+    }
+
+    pub fn execute<F : FnOnce() + Send + 'static>(&self, priority : Priority, job : F) {
+        self.push(priority, Box::new(job));
+    }
+
+    fn push(&self, priority : Priority, run : Task) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let seq = jobs.next_seq;
+        jobs.next_seq += 1;
+        jobs.heap.push(Job { priority, seq, run });
+
+        self.ready.notify_one();
+    }
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let jobs = self.jobs.lock().unwrap();
+                let mut jobs = self.ready.wait_while(jobs, |j| j.heap.is_empty()).unwrap();
+                jobs.heap.pop().unwrap()
+            };
+
+            (job.run)();
+        }
+    }
+}
+
+
+/// Outputs of a FIFO connection waiting for their turn, by arrival, with the peer that sent
+/// them
+#[derive(Default)]
+struct Fifo {
+    waiting : BTreeMap<u64, (IpAddr, Priority, Task)>,
+    /// whether an output is on the lane
+    running : bool
+}
+
+#[derive(Default)]
+struct Arrivals {
+    next_seq : u64,
+    /// connections that could still carry an output of a FIFO connection, with their peer
+    pending : BTreeMap<u64, IpAddr>,
+    connections : HashMap<u16, Fifo>
+}
+
+/// Outputs of the FIFO connections, served one at a time in the order they were accepted
+///
+/// Connections are classified in parallel, so an output only goes once the connections
+/// accepted before it from the same peer are known not to carry an earlier output. Only one
+/// output of a FIFO connection is on the lane at a time, the next one is put there when it
+/// is done, so that no worker waits
+pub struct Fifos {
+    lane : Arc<Lane>,
+    arrivals : Mutex<Arrivals>
+}
+
+impl Fifos {
+    /// Serve the outputs on the workers of `lane`
+    pub fn new(lane : Arc<Lane>) -> Arc<Fifos> {
+        Arc::new(Fifos {
+            lane,
+            arrivals : Mutex::new(Arrivals::default())
+        })
+    }
+
+    /// Number a connection from `peer` that was just accepted
+    pub fn accepted(&self, peer : IpAddr) -> u64 {
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        let seq = arrivals.next_seq;
+        arrivals.next_seq += 1;
+        arrivals.pending.insert(seq, peer);
+
+        seq
+    }
+
+    /// Connection `seq` does not carry an output of a FIFO connection
+    pub fn classified(self : &Arc<Self>, seq : u64) {
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        if arrivals.pending.remove(&seq).is_some() {
+            self.release(&mut arrivals);
+        }
+    }
+
+    /// Serve the output carried by connection `seq` after the previous ones of `conn_id`
+    pub fn execute<F : FnOnce() + Send + 'static>(self : &Arc<Self>, seq : u64, conn_id : u16,
+            priority : Priority, job : F) {
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        let peer = arrivals.pending.remove(&seq).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        arrivals.connections.entry(conn_id).or_default().waiting
+            .insert(seq, (peer, priority, Box::new(job)));
+        self.release(&mut arrivals);
+    }
+
+    /// Put the next output of the idle connections on the lane, unless a connection
+    /// accepted before it from the same peer could still carry an earlier output
+    fn release(self : &Arc<Self>, arrivals : &mut Arrivals) {
+        let Arrivals { pending, connections, .. } = arrivals;
+
+        for (conn_id, fifo) in connections.iter_mut() {
+            let seq = match fifo.waiting.iter().next() {
+                Some((seq, (peer, _, _))) if !fifo.running &&
+                    !pending.range(..*seq).any(|(_, p)| p == peer)  => *seq,
+                _                                                   => continue
+            };
+
+            let (_, priority, job) = fifo.waiting.remove(&seq).unwrap();
+            fifo.running = true;
+
+            let fifos = self.clone();
+            let conn_id = *conn_id;
+            self.lane.push(priority, Box::new(move || {
+                job();
+                fifos.done(conn_id);
+            }));
+        }
+
+        connections.retain(|_, f| f.running || !f.waiting.is_empty());
+    }
+
+    fn done(self : &Arc<Self>, conn_id : u16) {
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        if let Some(fifo) = arrivals.connections.get_mut(&conn_id) {
+            fifo.running = false;
+        }
+        self.release(&mut arrivals);
+    }
+}
+
+
+/// Serves the commands of the connections accepted by the EM, on lanes of workers
+pub struct Dispatcher {
+    /// read the start of the commands, so that a slow client does not hold the listener
+    classifiers : Vec<Arc<Classifier>>,
+    next_classifier : AtomicUsize,
+    classify_timeout : Duration,
+    /// Reset and the other short commands that manage the EM
+    control : Arc<Lane>,
+    /// commands that can take long, e.g. LoadModule or following the logs of a module
+    long : Arc<Lane>,
+    data : Arc<Lane>,
+    fifos : Arc<Fifos>
+}
+
+impl Dispatcher {
+    pub fn new(config : &Config) -> io::Result<Arc<Dispatcher>> {
+        let data = Lane::new(config.threads);
+
+        let mut classifiers = Vec::new();
+        let mut wakers = Vec::new();
+        for _ in 0..config.classify_threads.max(1) {
+            let (classifier, waker) = Classifier::new()?;
+            classifiers.push(classifier);
+            wakers.push(waker);
+        }
+
+        let dispatcher = Arc::new(Dispatcher {
+            classifiers,
+            next_classifier : AtomicUsize::new(0),
+            classify_timeout : Duration::from_millis(config.classify_timeout),
+            control : Lane::new(config.control_threads),
+            long : Lane::new(config.command_threads),
+            fifos : Fifos::new(data.clone()),
+            data
+        });
+
+        for (classifier, waker) in dispatcher.classifiers.iter().zip(wakers) {
+            let classifier = classifier.clone();
+            let dispatcher = dispatcher.clone();
+            thread::spawn(move || classifier.run(&dispatcher, waker));
+        }
+
+        Ok(dispatcher)
+    }
+
+    /// Workers for the events
+    pub fn data_lane(&self) -> Arc<Lane> {
+        self.data.clone()
+    }
+
+    /// Serve the command of a connection that was just accepted. Nothing is read from it on
+    /// the calling thread
+    pub fn dispatch(&self, em : Arc<EventManager>, stream : TcpStream) {
+        let peer = match stream.peer_addr() {
+            Ok(addr)    => addr.ip(),
+            Err(_)      => IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Connection error: {}", e);
+            return;
+        }
+
+        let pending = Pending {
+            em,
+            seq : self.fifos.accepted(peer),
+            stream,
+            prefix : Vec::new(),
+            settled : false,
+            deadline : Some(Instant::now() + self.classify_timeout)
+        };
+
+        let i = self.next_classifier.fetch_add(1, AtomicOrdering::Relaxed);
+        self.classifiers[i % self.classifiers.len()].push(pending);
+    }
+
+    /// Serve a command once the start of it was read
+    fn serve(&self, pending : Pending) {
+        let seq = pending.seq;
+        let route = classify(&pending.em, &pending.prefix);
+
+        if let Err(e) = pending.stream.set_nonblocking(false) {
+            error!("Connection error: {}", e);
+            self.fifos.classified(seq);
+            return;
+        }
+
+        let em = pending.em;
+        let mut s = Prefixed { prefix : io::Cursor::new(pending.prefix), inner : pending.stream };
+        let handle = move || em.handle(&mut s);
+
+        match route {
+            Route::Control                  => self.control.execute(Priority::Normal, handle),
+            Route::Long                     => self.long.execute(Priority::Normal, handle),
+            Route::Data(priority)           => self.data.execute(priority, handle),
+            Route::Fifo(conn_id, priority)  => {
+                self.fifos.execute(seq, conn_id, priority, handle);
+                return;
+            }
+        }
+
+        self.fifos.classified(seq);
+    }
+}
+
+
+/// A connection whose command is being classified
+struct Pending {
+    em : Arc<EventManager>,
+    /// number given by `Fifos::accepted`
+    seq : u64,
+    stream : TcpStream,
+    prefix : Vec<u8>,
+    /// whether the command is known not to be an output of a module
+    settled : bool,
+    /// until when FIFO outputs wait for this command, `None` once it is late
+    deadline : Option<Instant>
+}
+
+impl Pending {
+    /// Read what arrived of the start of the command, returns whether all of it is there
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 7];
+
+        loop {
+            let wanted = wanted(&self.prefix);
+            if self.prefix.len() >= wanted {
+                return Ok(true);
+            }
+
+            match self.stream.read(&mut buf[..wanted - self.prefix.len()]) {
+                Ok(0)   => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n)   => self.prefix.extend_from_slice(&buf[..n]),
+                Err(e)  => match e.kind() {
+                    io::ErrorKind::WouldBlock   => return Ok(false),
+                    io::ErrorKind::Interrupted  => (),
+                    _                           => return Err(e)
+                }
+            }
+        }
+    }
+}
+
+
+/// Reads the start of the commands of many connections at a time, without blocking on any
+/// of them
+struct Classifier {
+    incoming : Mutex<Vec<Pending>>,
+    /// written to when a connection is pushed, to wake up the thread in `poll`
+    wake : UnixStream
+}
+
+impl Classifier {
+    /// A classifier and the other end of its `wake` socket
+    fn new() -> io::Result<(Arc<Classifier>, UnixStream)> {
+        let (wake, waker) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        waker.set_nonblocking(true)?;
+
+        Ok((Arc::new(Classifier { incoming : Mutex::new(Vec::new()), wake }), waker))
+    }
+
+    fn push(&self, pending : Pending) {
+        self.incoming.lock().unwrap().push(pending);
+
+        // if the socket is full, the thread is going to wake up anyway
+        let _ = (&self.wake).write(&[0]);
+    }
+
+    fn run(&self, dispatcher : &Dispatcher, mut waker : UnixStream) {
+        let mut pending : Vec<Pending> = Vec::new();
+
+        loop {
+            let now = Instant::now();
+            let timeout = match pending.iter().filter_map(|p| p.deadline).min() {
+                Some(d) => d.saturating_duration_since(now).as_millis()
+                    .min(i32::MAX as u128) as i32,
+                None    => -1
+            };
+
+            let mut fds : Vec<libc::pollfd> = std::iter::once(waker.as_raw_fd())
+                .chain(pending.iter().map(|p| p.stream.as_raw_fd()))
+                .map(|fd| libc::pollfd { fd, events : libc::POLLIN, revents : 0 })
+                .collect();
+
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!("Failed to wait for the connections: {}", e);
+                }
+                continue;
+            }
+
+            let now = Instant::now();
+            let mut waiting = Vec::with_capacity(pending.len());
+            for (mut p, fd) in pending.drain(..).zip(&fds[1..]) {
+                let complete = match fd.revents {
+                    0   => Ok(false),
+                    _   => p.read()
+                };
+
+                match complete {
+                    Ok(true)    => dispatcher.serve(p),
+                    Ok(false)   => {
+                        let output = match p.prefix.first() {
+                            Some(code)  => is_module_output(code & !ERROR_CODES_FLAG),
+                            None        => true
+                        };
+                        if !p.settled && !output {
+                            p.settled = true;
+                            dispatcher.fifos.classified(p.seq);
+                        }
+
+                        // slow commands are still served, but FIFO outputs stop waiting
+                        // for them
+                        if matches!(p.deadline, Some(d) if d <= now) {
+                            warn!("Command of connection {} is slow to arrive", p.seq);
+                            p.deadline = None;
+                            dispatcher.fifos.classified(p.seq);
+                        }
+
+                        waiting.push(p);
+                    },
+                    Err(e)      => {
+                        error!("Connection error: {}", e);
+                        dispatcher.fifos.classified(p.seq);
+                    }
+                }
+            }
+            pending = waiting;
+
+            if fds[0].revents != 0 {
+                let mut buf = [0u8; 64];
+                while let Ok(n) = waker.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                pending.append(&mut self.incoming.lock().unwrap());
+            }
+        }
+    }
+}
+
+
+/// A TCP stream whose first bytes were already read
+pub struct Prefixed {
+    prefix : io::Cursor<Vec<u8>>,
+    inner : TcpStream
+}
+
+impl Read for Prefixed {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        match self.prefix.read(buf)? {
+            0   => self.inner.read(buf),
+            n   => Ok(n)
+        }
+    }
+}
+
+impl Write for Prefixed {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Stream for Prefixed {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr().ok()
+    }
+}
+
+
+/// Where a command is served
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Control,
+    /// a command that can take long
+    Long,
+    /// an event, with the priority of its connection
+    Data(Priority),
+    /// an output of a FIFO connection, served after the previous ones
+    Fifo(u16, Priority)
+}
+
+/// Where the command that starts with `prefix` should be served
+fn classify(em : &EventManager, prefix : &[u8]) -> Route {
+    let code = prefix[0] & !ERROR_CODES_FLAG;
+
+    match (is_control_command(code), is_long_command(code)) {
+        (true, false)   => Route::Control,
+        (true, true)    => Route::Long,
+        // payload is: [<entry or SM (u16)><conn ID (u16)>...]
+        (false, _)      => match prefix.len() {
+            7   => connection_route(em, code, bytes_to_u16(&prefix[5..])),
+            _   => Route::Data(Priority::Normal)
+        }
+    }
+}
+
+/// Length of the start of a command that is needed to classify it: the code and, for the
+/// events of a connection, the length and the first 4 bytes of the payload
+fn wanted(prefix : &[u8]) -> usize {
+    match prefix.first() {
+        None                                                        => 1,
+        Some(code) if !carries_connection(code & !ERROR_CODES_FLAG) => 1,
+        Some(_) if prefix.len() < 3                                 => 3,
+        Some(_)                                                     =>
+            3 + (bytes_to_u16(&prefix[1..3]) as usize).min(4)
+    }
+}
+
+fn carries_connection(code : u8) -> bool {
+    matches!((CommandCode::from_u8(code), ExtCommandCode::from_u8(code)),
+        (Some(CommandCode::RemoteOutput), _) | (Some(CommandCode::ModuleOutput), _) |
+        (Some(CommandCode::RemoteRequest), _) | (_, Some(ExtCommandCode::ModuleOutputAck)) |
+        (_, Some(ExtCommandCode::RemoteOutputAck)))
+}
+
+
+fn is_module_output(code : u8) -> bool {
+    matches!((CommandCode::from_u8(code), ExtCommandCode::from_u8(code)),
+        (Some(CommandCode::ModuleOutput), _) | (_, Some(ExtCommandCode::ModuleOutputAck)))
+}
+
+
+/// Route of an event of connection `conn_id`. Outputs of FIFO connections keep the order in
+/// which they are classified
+fn connection_route(em : &EventManager, code : u8, conn_id : u16) -> Route {
+    let fifo = is_module_output(code) && em.connections.lock().unwrap().get(&conn_id)
+        .map_or(false, |d| d.ordering == ConnectionOrdering::Fifo);
+
+    match fifo {
+        true    => Route::Fifo(conn_id, priority(em, conn_id)),
+        false   => Route::Data(priority(em, conn_id))
+    }
+}
+
+
+/// Priority of the events of connection `conn_id`
+pub fn priority(em : &EventManager, conn_id : u16) -> Priority {
+    em.priorities.lock().unwrap().get(&conn_id).copied().unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn priorities() {
+        let lane = Lane::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        // keep the worker busy until the other jobs are queued
+        let (release, wait) = mpsc::channel::<()>();
+        lane.execute(Priority::Normal, move || wait.recv().unwrap());
+
+        for (i, priority) in [Priority::Low, Priority::Normal, Priority::High, Priority::Normal]
+                .iter().enumerate() {
+            let tx = tx.clone();
+            lane.execute(*priority, move || tx.send(i).unwrap());
+        }
+
+        release.send(()).unwrap();
+        let order : Vec<usize> = (0..4).map(|_| rx.recv().unwrap()).collect();
+        assert_eq!(order, vec![2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn fifos() {
+        let lane = Lane::new(4);
+        let fifos = Fifos::new(lane);
+        let (tx, rx) = mpsc::channel();
+
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let seqs : Vec<u64> = (0..6).map(|_| fifos.accepted(local)).collect();
+
+        // connections from other peers are not held by the ones from this peer
+        let (tx3, rx3) = mpsc::channel();
+        let other = fifos.accepted(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        fifos.execute(other, 3, Priority::Normal, move || tx3.send(()).unwrap());
+        rx3.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        // the outputs of connection 1 are classified in reverse order, and the first one
+        // is slow, but the others wait for it without taking a worker
+        for i in (0..4).rev() {
+            let tx = tx.clone();
+            fifos.execute(seqs[i], 1, Priority::Normal, move || {
+                if i == 0 {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                tx.send((1, i)).unwrap();
+            });
+        }
+
+        // connection 2 waits until the connection accepted before it is classified, but
+        // not for connection 1
+        let tx2 = tx.clone();
+        fifos.execute(seqs[5], 2, Priority::Normal, move || tx2.send((2, 0)).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        fifos.classified(seqs[4]);
+
+        let order : Vec<(u16, usize)> = (0..5).map(|_| rx.recv().unwrap()).collect();
+        assert_eq!(order, vec![(2, 0), (1, 0), (1, 1), (1, 2), (1, 3)]);
+    }
+}
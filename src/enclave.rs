@@ -0,0 +1,334 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::convert::TryInto;
+
+use sha2::{Sha256, Digest};
+
+
+pub const SIGSTRUCT_SIZE : usize = 1808;
+
+const SIGSTRUCT_HEADER : [u8; 16] = [0x06, 0x00, 0x00, 0x00, 0xe1, 0x00, 0x00, 0x00,
+                                     0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+const SIGSTRUCT_HEADER2 : [u8; 16] = [0x01, 0x01, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00,
+                                      0x60, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+
+// records of the measurement stream stored in a .sgxs file
+const MEAS_ECREATE : &[u8; 8] = b"ECREATE\0";
+const MEAS_EADD : &[u8; 8] = b"EADD\0\0\0\0";
+const MEAS_EEXTEND : &[u8; 8] = b"EEXTEND\0";
+const MEAS_UNMEASURED : &[u8; 8] = b"UNMEASRD";
+const MEAS_BLOCK_SIZE : usize = 64;
+const MEAS_CHUNK_SIZE : usize = 256;
+
+
+/// Fields of a SIGSTRUCT that are relevant for the launch policy
+pub struct SigStruct {
+    pub mrsigner : [u8; 32],
+    pub enclavehash : [u8; 32],
+    pub isvprodid : u16,
+    pub isvsvn : u16
+}
+
+impl SigStruct {
+    pub fn parse(data : &[u8]) -> Result<SigStruct, String> {
+        if data.len() != SIGSTRUCT_SIZE {
+            return Err(format!("SIGSTRUCT has wrong size: {} (expected {})",
+                data.len(), SIGSTRUCT_SIZE));
+        }
+
+        if data[..16] != SIGSTRUCT_HEADER || data[24..40] != SIGSTRUCT_HEADER2 {
+            return Err("SIGSTRUCT has an invalid header".to_string());
+        }
+
+        // MRSIGNER is the hash of the signer's modulus (little-endian, as stored)
+        let mrsigner = Sha256::digest(&data[128..512]);
+
+        Ok(SigStruct {
+            mrsigner : mrsigner.into(),
+            enclavehash : data[960..992].try_into().unwrap(), // should never panic
+            isvprodid : u16::from_le_bytes([data[1024], data[1025]]),
+            isvsvn : u16::from_le_bytes([data[1026], data[1027]])
+        })
+    }
+
+    pub fn from_file(filename : &str) -> Result<SigStruct, String> {
+        let data = fs::read(filename).map_err(|e| format!("Cannot read {}: {}", filename, e))?;
+        SigStruct::parse(&data)
+    }
+}
+
+
+/// Compute the MRENCLAVE of an enclave from its measurement stream in SGXS format
+pub fn compute_mrenclave<R : Read>(sgxs : &mut R) -> Result<[u8; 32], String> {
+    let mut hasher = Sha256::new();
+    let mut block = [0u8; MEAS_BLOCK_SIZE];
+    let mut chunk = [0u8; MEAS_CHUNK_SIZE];
+    let mut first = true;
+
+    loop {
+        // read a whole block, or stop at EOF if we are at a block boundary
+        let mut read = 0;
+        while read < MEAS_BLOCK_SIZE {
+            match sgxs.read(&mut block[read..]) {
+                Ok(0)   => break,
+                Ok(n)   => read += n,
+                Err(e)  => return Err(format!("Cannot read SGXS: {}", e))
+            }
+        }
+
+        if read == 0 {
+            break;
+        }
+        if read != MEAS_BLOCK_SIZE {
+            return Err("SGXS file is truncated".to_string());
+        }
+
+        let tag = &block[..8];
+
+        if first && tag != MEAS_ECREATE {
+            return Err("SGXS file does not start with ECREATE".to_string());
+        }
+        first = false;
+
+        if tag == MEAS_ECREATE || tag == MEAS_EADD {
+            hasher.update(&block[..]);
+        }
+        else if tag == MEAS_EEXTEND {
+            sgxs.read_exact(&mut chunk).map_err(|_| "SGXS file is truncated".to_string())?;
+            hasher.update(&block[..]);
+            hasher.update(&chunk[..]);
+        }
+        else if tag == MEAS_UNMEASURED {
+            // data is loaded in the enclave but not part of the measurement
+            sgxs.read_exact(&mut chunk).map_err(|_| "SGXS file is truncated".to_string())?;
+        }
+        else {
+            return Err(format!("Unknown SGXS record: {:?}", tag));
+        }
+    }
+
+    if first {
+        return Err("SGXS file is empty".to_string());
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+
+struct PolicyEntry {
+    mrsigner : [u8; 32],
+    isvprodid : Option<u16>,
+    min_isvsvn : u16
+}
+
+/// Allowlist of enclave signers
+///
+/// Each non-empty line of a policy file has the form
+/// `<mrsigner (hex)> <isvprodid | *> <minimum isvsvn>`; `#` starts a comment.
+pub struct EnclavePolicy {
+    entries : Vec<PolicyEntry>
+}
+
+impl EnclavePolicy {
+    pub fn parse(policy : &str) -> Result<EnclavePolicy, String> {
+        let mut entries = Vec::new();
+
+        for (n, line) in policy.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim(); // should never panic
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("line {}: expected 3 fields, got {}", n + 1, fields.len()));
+            }
+
+            let mrsigner = match hex::decode(fields[0]) {
+                Ok(m) if m.len() == 32  => m.as_slice().try_into().unwrap(), // should never panic
+                _                       => return Err(format!("line {}: invalid MRSIGNER", n + 1))
+            };
+
+            let isvprodid = match fields[1] {
+                "*"     => None,
+                id      => Some(id.parse::<u16>()
+                            .map_err(|_| format!("line {}: invalid ISVPRODID", n + 1))?)
+            };
+
+            let min_isvsvn = fields[2].parse::<u16>()
+                .map_err(|_| format!("line {}: invalid ISVSVN", n + 1))?;
+
+            entries.push(PolicyEntry { mrsigner, isvprodid, min_isvsvn });
+        }
+
+        Ok(EnclavePolicy { entries })
+    }
+
+    pub fn from_file(filename : &str) -> Result<EnclavePolicy, String> {
+        let policy = fs::read_to_string(filename)
+            .map_err(|e| format!("Cannot read {}: {}", filename, e))?;
+        EnclavePolicy::parse(&policy)
+    }
+
+    pub fn check(&self, sig : &SigStruct) -> Result<(), String> {
+        let mut signer_known = false;
+
+        for entry in &self.entries {
+            if entry.mrsigner != sig.mrsigner {
+                continue;
+            }
+            signer_known = true;
+
+            if entry.isvprodid.map_or(true, |id| id == sig.isvprodid) &&
+                    sig.isvsvn >= entry.min_isvsvn {
+                return Ok(());
+            }
+        }
+
+        match signer_known {
+            true    => Err(format!("ISVPRODID {} / ISVSVN {} not allowed for MRSIGNER {}",
+                        sig.isvprodid, sig.isvsvn, hex::encode(sig.mrsigner))),
+            false   => Err(format!("MRSIGNER {} not allowed", hex::encode(sig.mrsigner)))
+        }
+    }
+}
+
+
+/// Check that the SIGSTRUCT matches the enclave and, if a policy is given, that the
+/// enclave is allowed to run on this node
+pub fn check_enclave(sgxs : &str, sig : &str, policy : Option<&EnclavePolicy>)
+        -> Result<(), String> {
+    let sigstruct = SigStruct::from_file(sig)?;
+
+    let file = File::open(sgxs).map_err(|e| format!("Cannot open {}: {}", sgxs, e))?;
+    let mrenclave = compute_mrenclave(&mut BufReader::new(file))?;
+
+    if mrenclave != sigstruct.enclavehash {
+        return Err(format!("MRENCLAVE mismatch: enclave is {}, SIGSTRUCT expects {}",
+            hex::encode(mrenclave), hex::encode(sigstruct.enclavehash)));
+    }
+
+    match policy {
+        Some(p) => p.check(&sigstruct),
+        None    => Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // MRENCLAVE of `sgxs()`, computed with Python's hashlib from the 64-byte ECREATE, EADD
+    // and EEXTEND updates described in the Intel SDM, not with this crate
+    const MRENCLAVE : &str = "15c5206a3a6565a9eb40fcd2626537d2f00278d5e8423a3fd06e0e8fd687bb2e";
+    const MRSIGNER : &str = "3d9c7f577b18b641d4d7f34641109989f8115842cb771ce0716c2de77db159b7";
+
+    fn record(tag : &[u8; 8], fields : &[u64]) -> Vec<u8> {
+        let mut block = tag.to_vec();
+        for field in fields {
+            block.extend_from_slice(&field.to_le_bytes());
+        }
+        block.resize(MEAS_BLOCK_SIZE, 0);
+        block
+    }
+
+    /// An enclave of one measured page chunk, and an unmeasured one
+    fn sgxs() -> Vec<u8> {
+        let mut ecreate = MEAS_ECREATE.to_vec();
+        ecreate.extend_from_slice(&1u32.to_le_bytes()); // SSA frame size
+        ecreate.extend_from_slice(&0x2000u64.to_le_bytes()); // enclave size
+        ecreate.resize(MEAS_BLOCK_SIZE, 0);
+
+        let mut sgxs = ecreate;
+        sgxs.extend(record(MEAS_EADD, &[0, 0x205])); // offset, SECINFO flags (PT_REG, R|X)
+        sgxs.extend(record(MEAS_EEXTEND, &[0]));
+        sgxs.extend((0..=255u8).collect::<Vec<u8>>());
+        sgxs.extend(record(MEAS_UNMEASURED, &[0x1000]));
+        sgxs.extend(vec![0xff; MEAS_CHUNK_SIZE]);
+        sgxs
+    }
+
+    fn sigstruct(enclavehash : &[u8], isvprodid : u16, isvsvn : u16) -> Vec<u8> {
+        let mut sig = vec![0u8; SIGSTRUCT_SIZE];
+        sig[..16].copy_from_slice(&SIGSTRUCT_HEADER);
+        sig[24..40].copy_from_slice(&SIGSTRUCT_HEADER2);
+        for (i, b) in sig[128..512].iter_mut().enumerate() {
+            *b = (i * 7 + 3) as u8;
+        }
+        sig[960..992].copy_from_slice(enclavehash);
+        sig[1024..1026].copy_from_slice(&isvprodid.to_le_bytes());
+        sig[1026..1028].copy_from_slice(&isvsvn.to_le_bytes());
+        sig
+    }
+
+    fn temp_file(name : &str, data : &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("em-enclave-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn mrenclave() {
+        let mrenclave = compute_mrenclave(&mut &sgxs()[..]).unwrap();
+        assert_eq!(hex::encode(mrenclave), MRENCLAVE);
+
+        let sgxs = sgxs();
+        assert!(compute_mrenclave(&mut &sgxs[..sgxs.len() - 1]).is_err());
+        assert!(compute_mrenclave(&mut &sgxs[MEAS_BLOCK_SIZE..]).is_err());
+        assert!(compute_mrenclave(&mut &[][..]).is_err());
+    }
+
+    #[test]
+    fn parse_sigstruct() {
+        let sig = SigStruct::parse(&sigstruct(&[0xaa; 32], 3, 7)).unwrap();
+        assert_eq!(hex::encode(sig.mrsigner), MRSIGNER);
+        assert_eq!(sig.enclavehash, [0xaa; 32]);
+        assert_eq!((sig.isvprodid, sig.isvsvn), (3, 7));
+
+        let mut invalid = sigstruct(&[0xaa; 32], 3, 7);
+        invalid[4] = 0;
+        assert!(SigStruct::parse(&invalid).is_err());
+        assert!(SigStruct::parse(&invalid[1..]).is_err());
+    }
+
+    #[test]
+    fn policy() {
+        let sig = SigStruct::parse(&sigstruct(&[0; 32], 3, 7)).unwrap();
+        let check = |policy : &str| EnclavePolicy::parse(policy).unwrap().check(&sig);
+
+        assert!(check(&format!("{} 3 7", MRSIGNER)).is_ok());
+        assert!(check(&format!("# any product\n{} * 5\n", MRSIGNER)).is_ok());
+        assert!(check(&format!("{} 4 0\n{} 3 8", MRSIGNER, MRSIGNER)).unwrap_err()
+            .starts_with("ISVPRODID 3 / ISVSVN 7 not allowed"));
+        assert!(check(&format!("{} * 0", MRENCLAVE)).unwrap_err()
+            .starts_with(&format!("MRSIGNER {} not allowed", MRSIGNER)));
+        assert!(check("").is_err());
+
+        assert!(EnclavePolicy::parse(&format!("{} 3", MRSIGNER)).is_err());
+        assert!(EnclavePolicy::parse("abcd * 0").is_err());
+        assert!(EnclavePolicy::parse(&format!("{} * -1", MRSIGNER)).is_err());
+    }
+
+    #[test]
+    fn check() {
+        let sgxs = temp_file("sgxs", &sgxs());
+        let good = temp_file("good.sig", &sigstruct(&hex::decode(MRENCLAVE).unwrap(), 3, 7));
+        let bad = temp_file("bad.sig", &sigstruct(&[0; 32], 3, 7));
+        let policy = EnclavePolicy::parse(&format!("{} 3 8", MRSIGNER)).unwrap();
+
+        let check = |sig : &PathBuf, policy : Option<&EnclavePolicy>|
+            check_enclave(sgxs.to_str().unwrap(), sig.to_str().unwrap(), policy);
+
+        assert!(check(&good, None).is_ok());
+        assert!(check(&bad, None).unwrap_err().starts_with("MRENCLAVE mismatch"));
+        assert!(check(&good, Some(&policy)).is_err());
+
+        for file in [sgxs, good, bad].iter() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::net::{TcpStream, SocketAddr, Ipv4Addr};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::protocol::{CommandError, Payload, RegisterModule};
+use crate::EventManager;
+
+pub use crate::protocol::Endpoint;
+
+
+/// How long to wait for a registered endpoint, to know if it is still in use
+const PROBE_TIMEOUT : Duration = Duration::from_millis(200);
+
+
+/// Path of the Unix socket a module may listen on
+///
+/// The socket is in the module's directory, so it is only accessible by the EM and by
+/// the module itself (see sandbox.rs)
+pub fn socket_path(dir : &Path, ind : u16) -> PathBuf {
+    dir.join(format!("sm{}.sock", ind))
+}
+
+
+/// What a module announced with RegisterModule
+#[derive(Clone, Debug)]
+pub struct Registration {
+    pub endpoint : Endpoint,
+    pub metadata : Vec<(String, String)>,
+    /// the `EM_MODULE_TOKEN` of the module, if the EM started it
+    pub token : Option<String>
+}
+
+impl Registration {
+    /// Parse a RegisterModule payload, see `RegisterModule`
+    pub fn parse(em : &EventManager, payload : &[u8]) -> Result<(u16, Registration), String> {
+        let register = RegisterModule::decode(payload).map_err(|e| e.to_string())?;
+
+        // modules can only point the EM to sockets in its own directory
+        if let Endpoint::Unix(path) = &register.endpoint {
+            if !path.is_absolute() || !path.starts_with(em.temp_dir()) ||
+                    path.components().any(|c| c == std::path::Component::ParentDir) {
+                return Err(format!("Socket path not allowed: {}", path.display()));
+            }
+        }
+
+        Ok((register.module_id, Registration {
+            endpoint : register.endpoint,
+            metadata : register.metadata,
+            token : register.token
+        }))
+    }
+}
+
+
+/// Record the endpoint of module `id`
+///
+/// If the EM started a module with this ID (its `module_id`, or its index for modules loaded
+/// without one), the registration must carry its token. Other IDs are only accepted with
+/// `Config::external_modules`, and cannot replace a registration whose endpoint is still in
+/// use.
+pub fn register(em : &EventManager, id : u16, registration : Registration)
+        -> Result<(), CommandError> {
+    let tokens : Vec<String> = em.modules.lock().unwrap().iter()
+        .filter(|(index, m)| m.get_options().module_id.unwrap_or(**index) == id)
+        .map(|(_, m)| m.get_token().to_string())
+        .collect();
+
+    let owner = match &registration.token {
+        Some(token) => tokens.contains(token),
+        None        => false
+    };
+
+    match (owner, tokens.is_empty()) {
+        (true, _)                                       => (),
+        (false, true) if em.config.external_modules    => (),
+        (false, true)                                   => return Err(CommandError::Unauthorized(
+            format!("Module {} was not started by this EM", id))),
+        (false, false)                                  => return Err(CommandError::Unauthorized(
+            format!("Invalid token for module {}", id)))
+    }
+
+    let current = em.endpoints.lock().unwrap().get(&id).map(|r| r.endpoint.clone());
+    if let (Some(endpoint), false) = (current, owner) {
+        if is_listening(&endpoint, PROBE_TIMEOUT) {
+            return Err(CommandError::BadRequest(format!("Module {} is already registered", id)));
+        }
+    }
+
+    em.endpoints.lock().unwrap().insert(id, registration);
+    Ok(())
+}
+
+
+/// Whether a module accepts connections on `endpoint`
+pub fn is_listening(endpoint : &Endpoint, timeout : Duration) -> bool {
+    match endpoint {
+        Endpoint::Tcp(port)     => TcpStream::connect_timeout(
+            &SocketAddr::from((Ipv4Addr::LOCALHOST, *port)), timeout).is_ok(),
+        Endpoint::Unix(path)    => UnixStream::connect(path).is_ok()
+    }
+}
+
+
+/// Endpoint of module `id`: the one it registered or, as a fallback, `PORT + id`
+pub fn get_endpoint(em : &EventManager, id : u16) -> Option<Endpoint> {
+    if let Some(r) = em.endpoints.lock().unwrap().get(&id) {
+        return Some(r.endpoint.clone());
+    }
+
+    em.port().checked_add(id).map(Endpoint::Tcp)
+}
+
+
+pub fn is_registered(em : &EventManager, id : u16) -> bool {
+    em.endpoints.lock().unwrap().contains_key(&id)
+}
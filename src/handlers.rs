@@ -1,91 +1,255 @@
-use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
-use reactive_net::{ResultCode, ResultMessage, EntrypointID};
+use std::collections::HashMap;
 
-use crate::connection::Connection;
+use reactive_net::{EntrypointID, CommandCode};
+
+use crate::connection::{Connection, Destinations};
 use crate::periodic::PeriodicTask;
 use crate::helpers::*;
 use crate::output::*;
 use crate::sm_loaders::*;
 use crate::time::*;
+use crate::modules::encode_log_lines;
+use crate::store;
+use crate::endpoint::{Registration, register};
+use crate::capabilities::Capabilities;
+use crate::protocol::*;
+use crate::upload::{self, UploadError};
+use crate::delivery;
+use crate::queue;
+use crate::commands::ExtCommandCode;
+
+use crate::EventManager;
+use log::{debug, info, error};
+
+
+pub fn handle_add_connection(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("add_connection payload received");
 
-use crate::{CONNECTIONS, PERIODIC_TASKS, MODULES};
-use log::{debug, warn, error};
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
 
+    let mut connections = em.connections.lock().unwrap();
 
-pub fn handle_add_connection(stream : &mut TcpStream) -> Option<ResultMessage> {
-    debug!("add_connection payload received");
+    match add_connection(&mut connections, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
+    }
+}
+
+
+/// Add a destination to a connection, which is created if needed
+fn add_connection(connections : &mut HashMap<u16, Destinations>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let conn = AddConnection::decode(payload)?;
+
+    debug!("Connection id {} to {}:{} (local: {}) module {}", conn.conn_id, conn.em_address,
+        conn.em_port, conn.local, conn.to_sm);
+
+    let dest = Connection::new(conn.to_sm, conn.em_address, conn.em_port, conn.local);
+    connections.entry(conn.conn_id).or_default().add(dest);
+
+    Ok(())
+}
+
+
+pub fn handle_remove_connection(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_remove_connection received");
 
     // read packet
-    let payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
-        Err(e) => {
-            error!("{}", e);
-            return Some(ResultMessage::new(ResultCode::InternalError, None));
-        }
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
     };
 
-    if payload.len() != 11 {
-        error!("Payload length is not correct: {}", payload.len());
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+    let mut connections = em.connections.lock().unwrap();
+
+    match remove_connection(&mut connections, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
     }
+}
 
-    let conn_id = bytes_to_u16(&payload[..2]);
-    let to_sm = bytes_to_u16(&payload[2..4]);
-    let local = payload[4] != 0;
-    let em_port = bytes_to_u16(&payload[5..7]);
-    let addr = match data_to_ipv4(&payload[7..11]) {
-        Ok(a) => a,
-        Err(e) => {
-            error!("{}", e);
-            return Some(ResultMessage::new(ResultCode::BadRequest, None));
-        }
+
+/// Remove a destination from a connection, which is removed with its last destination
+fn remove_connection(connections : &mut HashMap<u16, Destinations>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let conn = RemoveConnection::decode(payload)?;
+    let dest = Connection::new(conn.to_sm, conn.em_address, conn.em_port, conn.local);
+
+    let destinations = connections.get_mut(&conn.conn_id)
+        .ok_or(CommandError::UnknownConnection(conn.conn_id))?;
+
+    if !destinations.remove(&dest) {
+        return Err(CommandError::NotFound(format!(
+            "Connection {} has no destination {}:{} module {}", conn.conn_id, conn.em_address,
+            conn.em_port, conn.to_sm)));
+    }
+
+    if destinations.is_empty() {
+        connections.remove(&conn.conn_id);
+    }
+
+    debug!("Removed destination {}:{} module {} of connection {}", conn.em_address,
+        conn.em_port, conn.to_sm, conn.conn_id);
+    Ok(())
+}
+
+
+pub fn handle_set_connection_policy(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_set_connection_policy received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
     };
 
-    debug!("Connection id {} to {}:{} (local: {}) module {}", conn_id, addr, em_port, local, to_sm);
+    let mut connections = em.connections.lock().unwrap();
 
-    let mut connections = CONNECTIONS.lock().unwrap();
+    match set_connection_policy(&mut connections, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
+    }
+}
 
-    connections.insert(conn_id, Connection::new(to_sm, addr, em_port, local));
 
-    Some(ResultMessage::new(ResultCode::Ok, None))
+/// Choose how the outputs of a connection are delivered to its destinations
+fn set_connection_policy(connections : &mut HashMap<u16, Destinations>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let policy = SetConnectionPolicy::decode(payload)?;
+
+    // the connection must have been added first
+    connections.get_mut(&policy.conn_id)
+        .ok_or(CommandError::UnknownConnection(policy.conn_id))?
+        .policy = policy.policy;
+
+    debug!("Connection {} uses {:?}", policy.conn_id, policy.policy);
+    Ok(())
 }
 
 
-pub fn handle_call_entrypoint(stream : &mut TcpStream) -> Option<ResultMessage> {
-    debug!("call_entrypoint payload received");
+pub fn handle_set_connection_ordering(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_set_connection_ordering received");
 
     // read packet
-    let payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
-        Err(e) => {
-            error!("{}", e);
-            return Some(ResultMessage::new(ResultCode::InternalError, None));
-        }
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let mut connections = em.connections.lock().unwrap();
+
+    match set_connection_ordering(&mut connections, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
+    }
+}
+
+
+/// Choose whether the outputs of a connection are delivered in order
+fn set_connection_ordering(connections : &mut HashMap<u16, Destinations>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let ordering = SetConnectionOrdering::decode(payload)?;
+
+    // the connection must have been added first
+    connections.get_mut(&ordering.conn_id)
+        .ok_or(CommandError::UnknownConnection(ordering.conn_id))?
+        .ordering = ordering.ordering;
+
+    debug!("Connection {} is {:?}", ordering.conn_id, ordering.ordering);
+    Ok(())
+}
+
+
+pub fn handle_set_connection_priority(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_set_connection_priority received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
     };
 
-    if payload.len() <= 2 {
-        error!("Payload length is not correct");
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+    let mut priorities = em.priorities.lock().unwrap();
+
+    match set_connection_priority(&mut priorities, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
     }
+}
+
+
+/// Set the priority of the events of a connection. It also applies to the events received
+/// from other EMs, so the connection does not have to be added to this EM
+fn set_connection_priority(priorities : &mut HashMap<u16, Priority>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let priority = SetConnectionPriority::decode(payload)?;
+
+    match priority.priority {
+        Priority::Normal    => priorities.remove(&priority.conn_id),
+        p                   => priorities.insert(priority.conn_id, p)
+    };
 
-    let sm_id = bytes_to_u16(&payload[..2]);
+    debug!("Connection {} has priority {:?}", priority.conn_id, priority.priority);
+    Ok(())
+}
+
+
+pub fn handle_call_entrypoint(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("call_entrypoint payload received");
 
-    match connect_to_sm(sm_id, &payload[2..]) {
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let call = match CallEntrypoint::decode(&payload) {
+        Ok(c) => c,
+        Err(e) => return error_result(e.into())
+    };
+
+    match connect_to_sm(em, call.module, &call.sm_payload()) {
         Ok(r) => Some(r),
-        Err(e) => {
-            error!("{}", e);
-            Some(ResultMessage::new(ResultCode::InternalError, None))
-        }
+        Err(e) => error_result(e)
     }
 }
 
 
-pub fn handle_module_output(stream : &mut TcpStream) -> Option<ResultMessage> {
+/// With queues (`Config::queue_size`), outputs of FanOut connections to local modules are
+/// acknowledged when they are queued, not when the module has received them. Use
+/// ModuleOutputAck to know whether they were delivered
+pub fn handle_module_output(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
     debug!("handle_module_output payload received");
+    module_output(em, stream, false)
+}
 
+
+pub fn handle_module_output_ack(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_module_output_ack payload received");
+
+    // also outputs to another EM get a result, telling whether they were delivered
+    module_output(em, stream, true)
+}
+
+
+fn module_output(em : &EventManager, stream : &mut dyn Stream, ack : bool)
+        -> Option<ResultMessage> {
     // read packet
-    let payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
         Err(e) => {
             error!("{}", e);
@@ -93,66 +257,435 @@ pub fn handle_module_output(stream : &mut TcpStream) -> Option<ResultMessage> {
         }
     };
 
-    measure_time("module_output_before_dispatch");
+    measure_time(em, "module_output_before_dispatch");
 
-    if payload.len() <= 4 {
-        error!("Payload length is not correct");
-        return None;
-    }
+    // without `ack`, the module does not expect a result if the output is not valid
+    let output = match ModuleOutput::decode(&payload) {
+        Ok(o) => o,
+        Err(e) if ack => return error_result(e.into()),
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
 
-    let entry_id = bytes_to_u16(&payload[..2]);
-    let conn_id = bytes_to_u16(&payload[2..4]);
-    let connections = CONNECTIONS.lock().unwrap();
-    let conn = match connections.get(&conn_id) {
-        Some(c) => (*c).clone(), //copy in order to drop the map and release the lock for other threads
+    // outputs of FIFO connections are served one at a time by the dispatcher, in the order
+    // they are received
+    let connections = em.connections.lock().unwrap();
+    let destinations = match connections.get(&output.conn_id) {
+        Some(d) => d.clone(), //copy in order to drop the map and release the lock for other threads
         None => {
-            error!("No connection associated to {}", conn_id);
-            return None;
+            let e = CommandError::UnknownConnection(output.conn_id);
+            delivery::record(em, output.conn_id, output.entry, &e);
+            return match ack {
+                true    => Some(e.into()),
+                false   => None
+            };
         }
     };
     drop(connections); //release lock
 
-    let res = match conn.is_local_connection() {
-        true    => handle_local_connection(payload, conn),
-        false   => handle_remote_connection(payload, conn_id, entry_id, conn)
+    // the module gets the first result, or the first error if the output was not delivered
+    // (to any destination, or with `ack` to all of them)
+    let mut result = None;
+    let mut error = None;
+    let mut delivered = false;
+
+    let ordered = destinations.ordering == ConnectionOrdering::Fifo;
+    for res in deliver_output(em, &output, &destinations, ack, ordered) {
+        match res {
+            Ok(res) => {
+                delivered = true;
+                result = result.or(res);
+            },
+            Err(e)  => {
+                delivery::record(em, output.conn_id, output.entry, &e);
+                error = error.or(Some(e));
+            }
+        }
+    }
+
+    measure_time(em, "module_output_after_dispatch");
+
+    match error {
+        Some(e) if ack || !delivered    => Some(e.into()),
+        _                               => result
+    }
+}
+
+
+pub fn handle_load_sm(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_load_sm received");
+
+    match get_loader(em, &em.config.default_loader) {
+        Some(loader)    => command_result(load_module(em, loader, stream, LoadOptions::default())),
+        None            => error_result(CommandError::Internal(
+            format!("Default loader {} does not exist", em.config.default_loader)))
+    }
+}
+
+
+pub fn handle_load_module(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_load_module received");
+
+    // read options, the files follow
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
     };
 
-    match res {
-        Ok(res) => {
-            measure_time("module_output_after_dispatch");
-            res
+    let options = match LoadOptions::decode(&payload) {
+        Ok(o) => o,
+        Err(e) => return error_result(e.into())
+    };
+
+    let name = options.loader.clone().unwrap_or(em.config.default_loader.clone());
+
+    match get_loader(em, &name) {
+        Some(loader)    => command_result(load_module(em, loader, stream, options)),
+        None            => error_result(CommandError::NotFound(format!("Unknown loader: {}", name)))
+    }
+}
+
+
+pub fn handle_instantiate_sm(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_instantiate_sm received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let instantiate = match InstantiateSM::decode(&payload) {
+        Ok(i) if i.options.loader.is_none() && i.options.artifacts.is_empty() => i,
+        Ok(_) => return error_result(CommandError::BadRequest(
+            "Loader and artifacts are taken from the source module".to_string())),
+        Err(e) => return error_result(e.into())
+    };
+
+    command_result(instantiate_module(em, instantiate.source, instantiate.options))
+}
+
+
+pub fn handle_unload_sm(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_unload_sm received");
+
+    match read_module_index(stream) {
+        Ok(index)   => command_result(unload_module(em, index)),
+        Err(e)      => error_result(e)
+    }
+}
+
+
+pub fn handle_restart_sm(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_restart_sm received");
+
+    match read_module_index(stream) {
+        Ok(index)   => command_result(restart_module(em, index)),
+        Err(e)      => error_result(e)
+    }
+}
+
+
+fn read_module_index(stream : &mut dyn Stream) -> Result<u16, CommandError> {
+    // read packet
+    let payload = read_message_from(stream)
+        .map_err(|e| CommandError::Malformed(e.to_string()))?;
+
+    if payload.len() != 2 {
+        return Err(DecodeError::Length(payload.len()).into());
+    }
+
+    Ok(bytes_to_u16(&payload))
+}
+
+
+pub fn handle_register_module(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_register_module received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // only co-located modules can register
+    match stream.peer_address() {
+        Some(addr) if addr.ip().is_loopback() => (),
+        _ => return error_result(CommandError::Unauthorized(
+            "RegisterModule from a remote peer".to_string()))
+    }
+
+    let (id, registration) = match Registration::parse(em, &payload) {
+        Ok(r) => r,
+        Err(e) => return error_result(CommandError::Malformed(e))
+    };
+
+    let metadata : Vec<String> = registration.metadata.iter()
+        .map(|(k, v)| format!("{}={}", k, v)).collect();
+    let endpoint = registration.endpoint.clone();
+
+    match register(em, id, registration) {
+        Ok(_)   => {
+            info!("Module {} registered at {:?} [{}]", id, endpoint, metadata.join(", "));
+            Some(ResultMessage::new(ResultCode::Ok, None))
         },
-        Err(e)  => {
-            error!("handle_module_output: {}", e);
-            Some(ResultMessage::new(ResultCode::GenericError, None))
+        Err(e)  => error_result(e)
+    }
+}
+
+
+pub fn handle_hello(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_hello received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // payload is the protocol version of the peer (older EMs) or its capabilities, we
+    // always reply with ours
+    let caps = match payload.len() {
+        2   => Capabilities { version : bytes_to_u16(&payload), ..Capabilities::legacy() },
+        _   => match Capabilities::decode(&payload) {
+            Ok(caps)    => caps,
+            Err(e)      => return error_result(e.into())
         }
+    };
+
+    debug!("Peer has protocol version {}, features: {}", caps.version, caps.features.join(", "));
+
+    Some(ResultMessage::new(ResultCode::Ok, Some(Capabilities::local(em).encode())))
+}
+
+
+pub fn handle_upload_artifact(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_upload_artifact received");
+
+    // payload is: [<size><data>], the result contains the hash of the artifact
+    let mut buf : [u8; 4] = [0; 4];
+    if let Err(e) = stream.read_exact(&mut buf) {
+        return error_result(CommandError::Malformed(format!("Failed to read the size: {}", e)));
+    }
+
+    match store::put_from_stream(em, stream, bytes_to_u32(&buf)) {
+        Ok(hash)    => {
+            debug!("Stored artifact {}", hex::encode(hash));
+            Some(ResultMessage::new(ResultCode::Ok, Some(hash.to_vec())))
+        },
+        Err(e)      => error_result(e.into())
     }
 }
 
 
-pub fn handle_load_sm(stream: &mut TcpStream) -> Option<ResultMessage> {
-    debug!("handle_load_sm received");
+pub fn handle_remove_artifacts(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_remove_artifacts received");
 
-    match *crate::USE_SGX_LOADER {
-        true => load_sm_sgx(stream),
-        false => load_sm_native(stream)
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // the result contains the number of artifacts removed (u32)
+    let remove = match RemoveArtifacts::decode(&payload) {
+        Ok(r) => r,
+        Err(e) => return error_result(e.into())
+    };
+
+    let hashes = match remove.hash {
+        Some(hash)  => vec![hash],
+        None        => match store::list(em) {
+            Ok(h)   => h,
+            Err(e)  => return error_result(e.into())
+        }
+    };
+
+    let in_use : Vec<store::Hash> = em.modules.lock().unwrap().values()
+        .flat_map(|m| m.get_options().artifacts.clone())
+        .collect();
+
+    let mut removed : u32 = 0;
+    for hash in hashes.iter().filter(|h| !in_use.contains(h)) {
+        match store::remove(em, hash) {
+            Ok(_)   => removed += 1,
+            Err(e)  => return error_result(e.into())
+        }
+    }
+
+    if let (Some(hash), 0) = (remove.hash, removed) {
+        return error_result(CommandError::BadRequest(
+            format!("Artifact {} is used by a module", hex::encode(hash))));
+    }
+
+    debug!("Removed {} artifacts", removed);
+    Some(ResultMessage::new(ResultCode::Ok, Some(removed.to_be_bytes().to_vec())))
+}
+
+
+pub fn handle_upload_begin(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_upload_begin received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let begin = match UploadBegin::decode(&payload) {
+        Ok(b) => b,
+        Err(e) => return error_result(e.into())
+    };
+
+    match upload::begin(em, begin.size, begin.compression) {
+        Ok(id)  => {
+            debug!("Upload {}: {} bytes ({:?})", id, begin.size, begin.compression);
+            Some(ResultMessage::new(ResultCode::Ok, Some(id.to_be_bytes().to_vec())))
+        },
+        Err(e)  => Some(upload_error_result(e))
+    }
+}
+
+
+pub fn handle_upload_data(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_upload_data received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // the chunks follow, see `UploadData`
+    let UploadData { id, mut offset } = match UploadData::decode(&payload) {
+        Ok(d) => d,
+        Err(e) => return error_result(e.into())
+    };
+
+    loop {
+        let chunk = match read_message_from(stream) {
+            Ok(c) => c,
+            Err(e) => {
+                // the client can resume from the last acknowledged chunk
+                debug!("Upload {} interrupted at {}: {}", id, offset, e);
+                return None;
+            }
+        };
+
+        if chunk.is_empty() {
+            return Some(ResultMessage::new(ResultCode::Ok, Some(offset.to_be_bytes().to_vec())));
+        }
+
+        offset = match upload::write_chunk(em, id, offset, &chunk) {
+            Ok(o)   => o,
+            Err(e)  => return Some(upload_error_result(e))
+        };
+
+        let ack = ResultMessage::new(ResultCode::Ok, Some(offset.to_be_bytes().to_vec()));
+        if let Err(e) = write_result_to(stream, &ack) {
+            debug!("Upload {} interrupted at {}: {}", id, offset, e);
+            return None;
+        }
+    }
+}
+
+
+pub fn handle_upload_status(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_upload_status received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    if payload.len() != 4 {
+        return error_result(DecodeError::Length(payload.len()).into());
+    }
+
+    match upload::status(em, bytes_to_u32(&payload)) {
+        Ok(received)    => Some(ResultMessage::new(ResultCode::Ok,
+                            Some(received.to_be_bytes().to_vec()))),
+        Err(e)          => Some(upload_error_result(e))
+    }
+}
+
+
+pub fn handle_upload_finish(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_upload_finish received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    if payload.len() != 4 {
+        return error_result(DecodeError::Length(payload.len()).into());
     }
+
+    match upload::finish(em, bytes_to_u32(&payload)) {
+        Ok(hash)    => {
+            debug!("Stored artifact {}", hex::encode(hash));
+            Some(ResultMessage::new(ResultCode::Ok, Some(hash.to_vec())))
+        },
+        Err(e)      => Some(upload_error_result(e))
+    }
+}
+
+
+fn upload_error_result(e : UploadError) -> ResultMessage {
+    error!("{}", e);
+
+    // the client resumes from the offset in the payload
+    let err = match e {
+        UploadError::WrongOffset(o) | UploadError::Incomplete(o) =>
+            return ResultMessage::new(ResultCode::BadRequest, Some(o.to_be_bytes().to_vec())),
+        UploadError::UnknownUpload(_)   => CommandError::NotFound(e.to_string()),
+        UploadError::TooLarge |
+        UploadError::SizeLimit(_)       => CommandError::PayloadTooLarge(e.to_string()),
+        UploadError::TooMany(_)         => CommandError::BadRequest(e.to_string()),
+        UploadError::Io(e)              => e.into()
+    };
+
+    err.into()
 }
 
 
-pub fn handle_reset(_stream : &mut TcpStream) -> Option<ResultMessage> {
+pub fn handle_reset(em : &EventManager, _stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
     debug!("handle_reset received");
-    let mut connections = CONNECTIONS.lock().unwrap();
-    let mut tasks = PERIODIC_TASKS.lock().unwrap();
-    let mut modules = MODULES.lock().unwrap();
+    let mut connections = em.connections.lock().unwrap();
+    let mut tasks = em.periodic_tasks.lock().unwrap();
+    let mut modules = em.modules.lock().unwrap();
+    let mut endpoints = em.endpoints.lock().unwrap();
 
     connections.clear();
     tasks.clear();
-
-    for module in &mut *modules {
-        if let Err(_) = module.kill() {
-            warn!("Failed to kill module with PID {}", module.id());
-        }
+    endpoints.clear();
+    em.peers.lock().unwrap().clear();
+    em.priorities.lock().unwrap().clear();
+    delivery::clear(em);
+    queue::clear(em);
+    upload::clear(em);
+
+    for module in modules.values_mut() {
+        module.kill();
     }
     modules.clear();
 
@@ -160,41 +693,142 @@ pub fn handle_reset(_stream : &mut TcpStream) -> Option<ResultMessage> {
 }
 
 
-pub fn handle_register_entrypoint(stream : &mut TcpStream) -> Option<ResultMessage> {
+pub fn handle_register_entrypoint(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
     debug!("register_entrypoint payload received");
 
     // read packet
-    let payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
-        Err(e) => {
-            error!("{}", e);
-            return Some(ResultMessage::new(ResultCode::InternalError, None));
-        }
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
     };
 
-    if payload.len() != 8 {
-        error!("Payload length is not correct: {}", payload.len());
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+    let mut tasks = em.periodic_tasks.lock().unwrap();
+
+    match register_entrypoint(&mut tasks, &payload) {
+        Ok(_)   => Some(ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => error_result(e)
     }
+}
 
-    let module = bytes_to_u16(&payload[..2]);
-    let entry = bytes_to_u16(&payload[2..4]);
-    let frequency = bytes_to_u32(&payload[4..8]);
 
-    let mut tasks = PERIODIC_TASKS.lock().unwrap();
+fn register_entrypoint(tasks : &mut Vec<PeriodicTask>, payload : &[u8])
+        -> Result<(), CommandError> {
+    let task = RegisterEntrypoint::decode(payload)?;
 
-    tasks.push(PeriodicTask::new(module, entry, frequency));
+    tasks.push(PeriodicTask::new(task.module, task.entry, task.frequency, task.priority));
 
-    Some(ResultMessage::new(ResultCode::Ok, None))
+    Ok(())
+}
+
+
+pub fn handle_batch(em : &EventManager, stream : &mut dyn Stream, error_codes : bool)
+        -> Option<ResultMessage> {
+    debug!("handle_batch received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let Batch { atomic, commands } = match Batch::decode(&payload) {
+        Ok(b) => b,
+        Err(e) => return error_result(e.into())
+    };
+
+    debug!("Batch of {} commands (atomic: {})", commands.len(), atomic);
+
+    // hold the locks for the whole batch, so that nobody sees a partial state that
+    // is going to be rolled back
+    let mut connections = em.connections.lock().unwrap();
+    let mut tasks = em.periodic_tasks.lock().unwrap();
+    let mut priorities = em.priorities.lock().unwrap();
+    let snapshot = match atomic {
+        true    => Some((connections.clone(), tasks.clone(), priorities.clone())),
+        false   => None
+    };
+
+    // one result per sub-command, encoded as [<code (u8)><len (u16)><payload>], where the
+    // payload describes the error of failed sub-commands
+    let mut results = Vec::new();
+    let mut failed = false;
+
+    for (code, data) in commands {
+        let res = match (CommandCode::from_u8(code), ExtCommandCode::from_u8(code)) {
+            (Some(CommandCode::AddConnection), _)               =>
+                add_connection(&mut connections, &data),
+            (Some(CommandCode::RegisterEntrypoint), _)          =>
+                register_entrypoint(&mut tasks, &data),
+            (_, Some(ExtCommandCode::RemoveConnection))         =>
+                remove_connection(&mut connections, &data),
+            (_, Some(ExtCommandCode::SetConnectionPolicy))      =>
+                set_connection_policy(&mut connections, &data),
+            (_, Some(ExtCommandCode::SetConnectionOrdering))    =>
+                set_connection_ordering(&mut connections, &data),
+            (_, Some(ExtCommandCode::SetConnectionPriority))    =>
+                set_connection_priority(&mut priorities, &data),
+            _                                                   =>
+                Err(CommandError::IllegalCommand(code))
+        };
+
+        failed = res.is_err();
+        match res {
+            Ok(_)   => push_tlv(&mut results, ResultCode::Ok as u8, &[]),
+            Err(e)  => {
+                error!("Batch: {}", e);
+                let code = match error_codes {
+                    true    => e.code(),
+                    false   => e.code().legacy()
+                };
+                push_tlv(&mut results, code as u8, e.to_string().as_bytes());
+            }
+        }
+
+        if failed && atomic {
+            break;
+        }
+    }
+
+    match snapshot {
+        Some((c, t, p)) if failed   => {
+            error!("Batch failed, rolling back");
+            *connections = c;
+            *tasks = t;
+            *priorities = p;
+            Some(ResultMessage::new(ResultCode::GenericError, Some(results)))
+        },
+        _                           => Some(ResultMessage::new(ResultCode::Ok, Some(results)))
+    }
 }
 
 
-pub fn handle_remote_output(stream : &mut TcpStream) -> Option<ResultMessage> {
+pub fn handle_remote_output(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
     // received from another SM
     debug!("handle_remote_output received");
 
+    // the sender does not wait for a result
+    remote_output(em, stream, false);
+    None
+}
+
+
+pub fn handle_remote_output_ack(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    // received from another SM
+    debug!("handle_remote_output_ack received");
+
+    remote_output(em, stream, true)
+}
+
+
+/// Deliver a RemoteOutput, returns the result of the module or why it was not delivered.
+/// Without `ack`, the output may only be queued
+fn remote_output(em : &EventManager, stream : &mut dyn Stream, ack : bool)
+        -> Option<ResultMessage> {
     // read packet
-    let mut payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
         Err(e) => {
             error!("{}", e);
@@ -202,36 +836,37 @@ pub fn handle_remote_output(stream : &mut TcpStream) -> Option<ResultMessage> {
         }
     };
 
-    measure_time("remote_output_before_dispatch");
-
-    if payload.len() <= 6 {
-        error!("Payload length is not correct");
-        return None;
-    }
+    measure_time(em, "remote_output_before_dispatch");
 
-    let sm_id = bytes_to_u16(&payload[..2]);
-    debug!("SM ID: {}", sm_id);
+    let output = match RemoteOutput::decode(&payload) {
+        Ok(o) => o,
+        Err(e) => return error_result(e.into())
+    };
+    debug!("SM ID: {}", output.module);
 
     // HandleInput entrypoint
-    let entry_id = (EntrypointID::HandleInput as u16).to_be_bytes();
-    payload[0] = entry_id[0];
-    payload[1] = entry_id[1];
-
-    if let Err(e) = connect_to_sm(sm_id, &payload) {
-        debug!("{}", e);
-    }
+    let entry = EntrypointID::HandleInput as u16;
+    let data = output.sm_payload(entry);
+    let res = match queue::deliver(em, output.module, output.conn_id, entry, data, ack) {
+        Ok(res) => res.unwrap_or_else(|| ResultMessage::new(ResultCode::Ok, None)),
+        Err(e)  => {
+            delivery::record(em, output.conn_id, entry, &e);
+            e.into()
+        }
+    };
 
-    measure_time("remote_output_after_dispatch");
+    measure_time(em, "remote_output_after_dispatch");
 
-    None
+    Some(res)
 }
 
-pub fn handle_remote_request(stream : &mut TcpStream) -> Option<ResultMessage> {
+pub fn handle_remote_request(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
     // received from another SM
     debug!("handle_remote_request received");
 
     // read packet
-    let mut payload = match reactive_net::read_message(stream) {
+    let payload = match read_message_from(stream) {
         Ok(p) => p,
         Err(e) => {
             error!("{}", e);
@@ -239,29 +874,137 @@ pub fn handle_remote_request(stream : &mut TcpStream) -> Option<ResultMessage> {
         }
     };
 
-    measure_time("remote_request_before_dispatch");
+    measure_time(em, "remote_request_before_dispatch");
 
-    if payload.len() <= 6 {
-        error!("Payload length is not correct");
-        return None;
-    }
-
-    let sm_id = bytes_to_u16(&payload[..2]);
-    debug!("SM ID: {}", sm_id);
+    let request = match RemoteRequest::decode(&payload) {
+        Ok(r) => r,
+        Err(e) => return error_result(e.into())
+    };
+    debug!("SM ID: {}", request.module);
 
     // HandleHandler entrypoint
-    let entry_id = (EntrypointID::HandleHandler as u16).to_be_bytes();
-    payload[0] = entry_id[0];
-    payload[1] = entry_id[1];
+    let data = request.sm_payload(EntrypointID::HandleHandler as u16);
 
-    match connect_to_sm(sm_id, &payload) {
+    match connect_to_sm(em, request.module, &data) {
         Ok(res)     => {
-            measure_time("remote_request_after_dispatch");
+            measure_time(em, "remote_request_after_dispatch");
             Some(res)
         },
-        Err(e)      => {
-            error!("{}", e);
-            Some(ResultMessage::new(ResultCode::InternalError, None))
+        Err(e)      => error_result(e)
+    }
+}
+
+
+pub fn handle_get_dead_letters(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_get_dead_letters received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // payload is: [<clear (u8)>], to reset the counters and the log after reading them
+    if payload.len() != 1 {
+        return error_result(DecodeError::Length(payload.len()).into());
+    }
+
+    let letters = delivery::report(em, payload[0] != 0);
+    Some(ResultMessage::new(ResultCode::Ok, Some(letters.encode())))
+}
+
+
+pub fn handle_get_queue_stats(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_get_queue_stats received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    // payload is: [<reset (u8)>], to reset the counters after reading them
+    if payload.len() != 1 {
+        return error_result(DecodeError::Length(payload.len()).into());
+    }
+
+    let stats = queue::stats(em, payload[0] != 0);
+    Some(ResultMessage::new(ResultCode::Ok, Some(stats.encode())))
+}
+
+
+pub fn handle_get_module_logs(em : &EventManager, stream : &mut dyn Stream)
+        -> Option<ResultMessage> {
+    debug!("handle_get_module_logs received");
+
+    // read packet
+    let payload = match read_message_from(stream) {
+        Ok(p) => p,
+        Err(e) => return error_result(CommandError::Malformed(e.to_string()))
+    };
+
+    let GetModuleLogs { index, tail, follow } = match GetModuleLogs::decode(&payload) {
+        Ok(g) => g,
+        Err(e) => return error_result(e.into())
+    };
+
+    let logs = match em.modules.lock().unwrap().get(&index) {
+        Some(m) => m.get_logs(),
+        None    => return error_result(CommandError::UnknownModule(index))
+    };
+
+    // the result is followed by messages containing log lines, and by an empty message
+    if let Err(e) = write_result_to(stream, &ResultMessage::new(ResultCode::Ok, None)) {
+        error!("{}", e);
+        return None;
+    }
+
+    // a quiet module must not hold a worker forever
+    let deadline = Instant::now() + Duration::from_secs(em.config.module_log_follow);
+
+    let (mut lines, mut next) = logs.tail(tail as usize);
+    loop {
+        for msg in encode_log_lines(&lines) {
+            if let Err(e) = write_message_to(stream, &msg) {
+                debug!("Stop sending logs of module {}: {}", index, e);
+                return None;
+            }
         }
+
+        if !follow || Instant::now() >= deadline {
+            break;
+        }
+
+        let wait = deadline.saturating_duration_since(Instant::now()).min(Duration::from_secs(1));
+        let (new_lines, new_next, closed) = logs.wait_from(next, wait);
+        if closed && new_lines.is_empty() {
+            break;
+        }
+
+        lines = new_lines;
+        next = new_next;
+    }
+
+    if let Err(e) = write_message_to(stream, &[]) {
+        error!("{}", e);
+    }
+
+    None
+}
+
+
+/// Log `e` and turn it into the result sent to the caller
+fn error_result(e : CommandError) -> Option<ResultMessage> {
+    error!("{}", e);
+    Some(e.into())
+}
+
+
+fn command_result(res : Result<ResultMessage, CommandError>) -> Option<ResultMessage> {
+    match res {
+        Ok(r)   => Some(r),
+        Err(e)  => error_result(e)
     }
 }
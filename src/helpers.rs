@@ -1,42 +1,14 @@
-use std::net::Ipv4Addr;
-use std::net::TcpStream;
 use std::io::prelude::*;
 use std::fs::OpenOptions;
 use std::convert::TryFrom;
 
+pub use crate::protocol::{bytes_to_u16, bytes_to_u32};
+use crate::protocol::Stream;
 
-pub fn get_sm_index() -> u16 {
-    let mut ind = crate::SM_INDEX.lock().unwrap();
 
-    let new = *ind;
-    *ind += 1;
-
-    new
-}
-
-
-pub fn data_to_ipv4(data : &[u8]) -> Result<Ipv4Addr, &str> {
-    if data.len() != 4 {
-        Err("Data len not valid")
-    }
-    else {
-        Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
-    }
-}
-
-
-pub fn bytes_to_u16(buf : &[u8]) -> u16 {
-    u16::from_be_bytes([buf[0], buf[1]])
-}
-
-
-pub fn bytes_to_u32(buf : &[u8]) -> u32 {
-    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
-}
-
-
-pub fn write_to_file(stream : &mut TcpStream, size : u32, filename : &str) -> std::io::Result<()> {
-    let mut file = OpenOptions::new().write(true).create(true).open(filename)?;
+pub fn write_to_file(stream : &mut dyn Stream, size : u32, filename : &str)
+        -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(filename)?;
 
     // read data
     let mut buf : [u8; 1024] = [0; 1024];
@@ -45,7 +17,7 @@ pub fn write_to_file(stream : &mut TcpStream, size : u32, filename : &str) -> st
         let min = std::cmp::min(size_left, 1024);
 
         stream.read_exact(&mut buf[..min])?;
-        file.write(&buf[..min])?;
+        file.write_all(&buf[..min])?;
 
         size_left -= min;
 
@@ -56,3 +28,37 @@ pub fn write_to_file(stream : &mut TcpStream, size : u32, filename : &str) -> st
 
     Ok(())
 }
+
+
+/// Split a list of `[<tag (u8)><len (u16)><value>]` entries
+pub fn parse_tlv(data : &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    let mut entries = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        if data.len() - i < 3 {
+            return Err("Truncated option header".to_string());
+        }
+
+        let tag = data[i];
+        let len = bytes_to_u16(&data[i + 1..i + 3]) as usize;
+        i += 3;
+
+        if data.len() - i < len {
+            return Err(format!("Truncated value for option {}", tag));
+        }
+
+        entries.push((tag, &data[i..i + len]));
+        i += len;
+    }
+
+    Ok(entries)
+}
+
+
+/// Append a `[<tag (u8)><len (u16)><value>]` entry to `buf`
+pub fn push_tlv(buf : &mut Vec<u8>, tag : u8, value : &[u8]) {
+    buf.push(tag);
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
@@ -0,0 +1,41 @@
+//! Event manager of the Authentic Execution framework
+//!
+//! `EventManager` runs an EM, the `protocol` and `client` modules can be used to talk to one
+
+pub mod protocol;
+pub mod commands;
+pub mod client;
+
+mod manager;
+mod handlers;
+mod helpers;
+mod output;
+mod connection;
+mod sm_loaders;
+mod periodic;
+mod time;
+mod enclave;
+mod sandbox;
+mod modules;
+mod store;
+mod upload;
+mod endpoint;
+mod capabilities;
+mod delivery;
+mod queue;
+mod dispatch;
+
+pub use manager::{Config, EventManager};
+pub use enclave::EnclavePolicy;
+pub use sandbox::SandboxConfig;
+pub use queue::Overflow;
+pub use capabilities::Capabilities;
+
+
+/// Internal parsers, exposed for the fuzz targets in `fuzz/`
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    pub use crate::helpers::parse_tlv;
+    pub use crate::endpoint::Registration;
+    pub use crate::enclave::{SigStruct, compute_mrenclave};
+}
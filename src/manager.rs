@@ -0,0 +1,365 @@
+use std::env;
+use std::io;
+use std::net::{TcpListener, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::fs;
+
+use log::{debug, error};
+use reactive_net::CommandCode;
+
+use crate::handlers;
+use crate::commands::{ExtCommandCode, ERROR_CODES_FLAG};
+use crate::connection::Destinations;
+use crate::periodic::{self, PeriodicTask};
+use crate::modules::Module;
+use crate::sm_loaders::{self, ModuleLoader};
+use crate::enclave::EnclavePolicy;
+use crate::sandbox::SandboxConfig;
+use crate::endpoint::Registration;
+use crate::capabilities::Capabilities;
+use crate::upload::Uploads;
+use crate::delivery::DeadLetterLog;
+use crate::queue::{Overflow, Queue};
+use crate::dispatch::Dispatcher;
+use crate::protocol::{CommandError, Priority, ResultMessage, Stream, write_result_to};
+
+
+/// Configuration of an event manager
+pub struct Config {
+    /// port to listen on, 0 to pick a free one
+    pub port : u16,
+    pub measure_time : bool,
+    pub default_loader : String,
+    /// command line used by the sgx loader, with placeholders (see `expand_placeholders`)
+    pub sgx_runner : Vec<String>,
+    pub sgx_policy : Option<EnclavePolicy>,
+    pub sandbox : SandboxConfig,
+    /// where artifacts are kept, by default in the temporary directory of the EM
+    pub store_dir : Option<PathBuf>,
+    /// chunked uploads in progress at the same time
+    pub max_uploads : usize,
+    /// largest size declared by a chunked upload, in bytes
+    pub max_upload_size : u32,
+    /// how long an idle chunked upload is kept, in seconds
+    pub upload_ttl : u64,
+    /// accept RegisterModule for the IDs of modules that the EM did not start, e.g. modules
+    /// started by hand. Those registrations need no token
+    pub external_modules : bool,
+    /// lines of stdout and stderr kept for each module, 0 to keep none
+    pub module_log_lines : usize,
+    pub module_log_forward : bool,
+    /// longest time a GetModuleLogs request follows the output of a module, in seconds
+    pub module_log_follow : u64,
+    pub periodic_tasks : bool,
+    /// workers for the events
+    pub threads : usize,
+    /// workers reserved for the short commands that manage the EM, e.g. Reset or
+    /// AddConnection
+    pub control_threads : usize,
+    /// workers for the commands that can take long, e.g. LoadSM or GetModuleLogs
+    pub command_threads : usize,
+    /// threads reading the start of the commands to dispatch them, each one waits for many
+    /// connections at a time
+    pub classify_threads : usize,
+    /// how long the start of a command may take to arrive, in milliseconds. Slower commands
+    /// are still served, but the outputs of FIFO connections stop waiting for them
+    pub classify_timeout : u64,
+    /// number of undelivered outputs kept in the dead-letter log
+    pub dead_letters : usize,
+    /// outputs queued for each module, 0 to deliver them from the worker that receives them.
+    /// With queues, ModuleOutput gets an `Ok` result as soon as the output is queued, and
+    /// delivery errors are only recorded as dead letters, unless the connection has a
+    /// delivery policy other than FanOut
+    pub queue_size : usize,
+    /// what happens to outputs for a module whose queue is full
+    pub queue_overflow : Overflow
+}
+
+impl Config {
+    /// Default configuration, without sandbox
+    pub fn new(port : u16) -> Config {
+        Config {
+            port,
+            measure_time : false,
+            default_loader : "sgx".to_string(),
+            sgx_runner : vec!["ftxsgx-runner".to_string(), "-s".to_string(),
+                "coresident".to_string(), "{sgxs}".to_string()],
+            sgx_policy : None,
+            sandbox : SandboxConfig::disabled(),
+            store_dir : None,
+            max_uploads : 16,
+            max_upload_size : 1 << 30,
+            upload_ttl : 600,
+            external_modules : false,
+            module_log_lines : 1000,
+            module_log_forward : true,
+            module_log_follow : 600,
+            periodic_tasks : false,
+            threads : 16,
+            control_threads : 2,
+            command_threads : 4,
+            classify_threads : 1,
+            classify_timeout : 1000,
+            dead_letters : 100,
+            queue_size : 0,
+            queue_overflow : Overflow::Block
+        }
+    }
+
+    /// Configuration from the `EM_*` environment variables. Panics if one is not valid
+    pub fn from_env() -> Config {
+        let port = env::var("EM_PORT").expect("Missing EM_PORT environment variable")
+            .parse::<u16>().expect("Port must be an u16!");
+
+        let default_loader = match env::var("EM_LOADER") {
+            Ok(loader)  => loader,
+            // EM_SGX is kept for backwards compatibility
+            Err(_)      => match env_or("EM_SGX", true) {
+                true    => "sgx".to_string(),
+                false   => "native".to_string()
+            }
+        };
+
+        let sgx_runner : Vec<String> = env::var("EM_SGX_RUNNER")
+            .unwrap_or("ftxsgx-runner -s coresident {sgxs}".to_string())
+            .split_whitespace().map(|s| s.to_string()).collect();
+        if sgx_runner.is_empty() {
+            panic!("EM_SGX_RUNNER must not be empty");
+        }
+
+        let sgx_policy = env::var("EM_SGX_POLICY").ok().map(|f| EnclavePolicy::from_file(&f)
+            .expect("Failed to load EM_SGX_POLICY"));
+
+        Config {
+            port,
+            measure_time : env_or("EM_MEASURE_TIME", false),
+            default_loader,
+            sgx_runner,
+            sgx_policy,
+            sandbox : SandboxConfig::from_env(),
+            store_dir : env::var("EM_STORE_DIR").ok().map(PathBuf::from),
+            max_uploads : env_or("EM_MAX_UPLOADS", 16),
+            max_upload_size : env_or("EM_MAX_UPLOAD_SIZE", 1 << 30),
+            upload_ttl : env_or("EM_UPLOAD_TTL", 600),
+            external_modules : env_or("EM_EXTERNAL_MODULES", false),
+            module_log_lines : env_or("EM_MODULE_LOG_LINES", 1000),
+            module_log_forward : env_or("EM_MODULE_LOG_FORWARD", true),
+            module_log_follow : env_or("EM_MODULE_LOG_FOLLOW", 600),
+            periodic_tasks : env_or("EM_PERIODIC_TASKS", false),
+            threads : env_or("EM_THREADS", 16),
+            control_threads : env_or("EM_CONTROL_THREADS", 2),
+            command_threads : env_or("EM_COMMAND_THREADS", 4),
+            classify_threads : env_or("EM_CLASSIFY_THREADS", 1),
+            classify_timeout : env_or("EM_CLASSIFY_TIMEOUT", 1000),
+            dead_letters : env_or("EM_DEAD_LETTERS", 100),
+            queue_size : env_or("EM_QUEUE_SIZE", 0),
+            queue_overflow : env_or("EM_QUEUE_OVERFLOW", Overflow::Block)
+        }
+    }
+}
+
+fn env_or<T : std::str::FromStr>(var : &str, default : T) -> T {
+    match env::var(var) {
+        Ok(v)   => v.parse::<T>().unwrap_or_else(|_| panic!("Invalid value for {}", var)),
+        Err(_)  => default
+    }
+}
+
+
+/// State of an event manager
+///
+/// Several EMs can run in the same process, each one with its own port, directory and
+/// modules.
+pub struct EventManager {
+    pub(crate) config : Config,
+    listener : TcpListener,
+    port : u16,
+    pub(crate) temp_dir : tempfile::TempDir,
+    pub(crate) store_dir : PathBuf,
+    pub(crate) loaders : HashMap<&'static str, Box<dyn ModuleLoader>>,
+    sm_index : Mutex<u16>,
+    /// destinations of each connection
+    pub(crate) connections : Mutex<HashMap<u16, Destinations>>,
+    pub(crate) periodic_tasks : Mutex<Vec<PeriodicTask>>,
+    pub(crate) modules : Mutex<HashMap<u16, Module>>,
+    pub(crate) endpoints : Mutex<HashMap<u16, Registration>>,
+    pub(crate) peers : Mutex<HashMap<SocketAddrV4, Capabilities>>,
+    pub(crate) uploads : Mutex<Uploads>,
+    pub(crate) dead_letters : Arc<Mutex<DeadLetterLog>>,
+    /// outputs waiting for each module
+    pub(crate) queues : Mutex<HashMap<u16, Arc<Queue>>>,
+    /// priority of the events of each connection, if not normal
+    pub(crate) priorities : Mutex<HashMap<u16, Priority>>
+}
+
+impl EventManager {
+    /// Create an EM listening on `config.port`
+    pub fn bind(config : Config) -> io::Result<EventManager> {
+        let loaders = sm_loaders::default_loaders();
+        if !loaders.contains_key(config.default_loader.as_str()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Unknown loader: {}", config.default_loader)));
+        }
+
+        let temp_dir = tempfile::tempdir()?;
+        let store_dir = match &config.store_dir {
+            Some(d) => d.clone(),
+            None    => temp_dir.path().join("store")
+        };
+        fs::create_dir_all(&store_dir)?;
+
+        let listener = TcpListener::bind(("0.0.0.0", config.port))?;
+        let port = listener.local_addr()?.port();
+        let dead_letters = DeadLetterLog::new(config.dead_letters);
+
+        Ok(EventManager {
+            config,
+            listener,
+            port,
+            temp_dir,
+            store_dir,
+            loaders,
+            sm_index : Mutex::new(0),
+            connections : Mutex::new(HashMap::new()),
+            periodic_tasks : Mutex::new(Vec::new()),
+            modules : Mutex::new(HashMap::new()),
+            endpoints : Mutex::new(HashMap::new()),
+            peers : Mutex::new(HashMap::new()),
+            uploads : Mutex::new(Uploads::default()),
+            dead_letters : Arc::new(Mutex::new(dead_letters)),
+            queues : Mutex::new(HashMap::new()),
+            priorities : Mutex::new(HashMap::new())
+        })
+    }
+
+    /// Port the EM is listening on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Directory with the files of the modules
+    pub fn temp_dir(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    pub fn store_dir(&self) -> &Path {
+        &self.store_dir
+    }
+
+    pub(crate) fn get_sm_index(&self) -> u16 {
+        let mut ind = self.sm_index.lock().unwrap();
+
+        let new = *ind;
+        *ind += 1;
+
+        new
+    }
+
+    /// Serve commands until the listener fails
+    pub fn run(self : Arc<Self>) -> io::Result<()> {
+        // commands that manage the EM do not wait behind the events
+        let dispatcher = Dispatcher::new(&self.config)?;
+
+        // periodic tasks are only enabled on request
+        if self.config.periodic_tasks {
+            let em = self.clone();
+            let lane = dispatcher.data_lane();
+            thread::spawn(move || periodic::run_periodic_tasks(em, lane));
+        }
+
+        for stream in self.listener.incoming() {
+            debug!("Received new connection");
+
+            match stream {
+                Ok(s)   => dispatcher.dispatch(self.clone(), s),
+                Err(e)  => error!("Connection error: {}", e)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle one command read from `stream`
+    pub fn handle(&self, stream : &mut dyn Stream) {
+        let em = self;
+        let mut buf : [u8; 1] = [0; 1];
+
+        // read first byte: message type
+        if stream.read_exact(&mut buf).is_err() {
+            error!("Error while reading from socket");
+            return;
+        };
+
+        // the flag tells whether the caller understands all result codes
+        let error_codes = buf[0] & ERROR_CODES_FLAG != 0;
+        let code = buf[0] & !ERROR_CODES_FLAG;
+
+        // check code
+        let res = match CommandCode::from_u8(code) {
+            Some(r) => match r {
+                CommandCode::AddConnection      => handlers::handle_add_connection(em, stream),
+                CommandCode::CallEntrypoint     => handlers::handle_call_entrypoint(em, stream),
+                CommandCode::RemoteOutput       => handlers::handle_remote_output(em, stream),
+                CommandCode::LoadSM             => handlers::handle_load_sm(em, stream),
+                CommandCode::Reset              => handlers::handle_reset(em, stream),
+                CommandCode::RegisterEntrypoint => handlers::handle_register_entrypoint(em, stream),
+                CommandCode::ModuleOutput       => handlers::handle_module_output(em, stream),
+                CommandCode::RemoteRequest      => handlers::handle_remote_request(em, stream)
+            },
+            None    => match ExtCommandCode::from_u8(code) {
+                Some(r) => match r {
+                    ExtCommandCode::LoadModule      => handlers::handle_load_module(em, stream),
+                    ExtCommandCode::GetModuleLogs   => handlers::handle_get_module_logs(em, stream),
+                    ExtCommandCode::UploadArtifact  => handlers::handle_upload_artifact(em, stream),
+                    ExtCommandCode::UploadBegin     => handlers::handle_upload_begin(em, stream),
+                    ExtCommandCode::UploadData      => handlers::handle_upload_data(em, stream),
+                    ExtCommandCode::UploadStatus    => handlers::handle_upload_status(em, stream),
+                    ExtCommandCode::UploadFinish    => handlers::handle_upload_finish(em, stream),
+                    ExtCommandCode::InstantiateSM   => handlers::handle_instantiate_sm(em, stream),
+                    ExtCommandCode::UnloadSM        => handlers::handle_unload_sm(em, stream),
+                    ExtCommandCode::RestartSM       => handlers::handle_restart_sm(em, stream),
+                    ExtCommandCode::RegisterModule  => handlers::handle_register_module(em, stream),
+                    ExtCommandCode::Hello           => handlers::handle_hello(em, stream),
+                    ExtCommandCode::Batch           => handlers::handle_batch(em, stream, error_codes),
+                    ExtCommandCode::ModuleOutputAck => handlers::handle_module_output_ack(em, stream),
+                    ExtCommandCode::RemoteOutputAck => handlers::handle_remote_output_ack(em, stream),
+                    ExtCommandCode::GetDeadLetters  => handlers::handle_get_dead_letters(em, stream),
+                    ExtCommandCode::RemoveConnection => handlers::handle_remove_connection(em, stream),
+                    ExtCommandCode::SetConnectionPolicy =>
+                        handlers::handle_set_connection_policy(em, stream),
+                    ExtCommandCode::SetConnectionOrdering =>
+                        handlers::handle_set_connection_ordering(em, stream),
+                    ExtCommandCode::GetQueueStats   => handlers::handle_get_queue_stats(em, stream),
+                    ExtCommandCode::SetConnectionPriority =>
+                        handlers::handle_set_connection_priority(em, stream),
+                    ExtCommandCode::RemoveArtifacts => handlers::handle_remove_artifacts(em, stream)
+                },
+                None    => {
+                    let e = CommandError::IllegalCommand(code);
+                    error!("{}", e);
+                    Some(e.into())
+                }
+            }
+        };
+
+        debug!("Result: {:?}", res);
+
+        let res = match error_codes {
+            true    => res,
+            false   => res.map(ResultMessage::legacy)
+        };
+
+        if let Some(response) = res {
+            if let Err(s) = write_result_to(stream, &response) {
+                error!("{}", s);
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::io::BufReader;
+use std::process::{Child, ExitStatus};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::helpers::push_tlv;
+use crate::sm_loaders::{ModuleFiles, LoadOptions};
+use crate::EventManager;
+
+
+const MAX_LINE_LEN : usize = 4096;
+const TOKEN_SIZE : usize = 16;
+
+
+/// A module started by the EM
+pub struct Module {
+    loader : &'static str,
+    files : ModuleFiles,
+    options : LoadOptions,
+    child : Child,
+    /// secret passed to the process, that it sends back in RegisterModule
+    token : String,
+    logs : Arc<ModuleLogs>
+}
+
+impl Module {
+    /// Wrap a newly spawned module, capturing its stdout and stderr (if piped)
+    pub fn new(em : &EventManager, loader : &'static str, files : ModuleFiles,
+            options : LoadOptions, mut child : Child, token : String) -> Module {
+        let logs = Arc::new(ModuleLogs::new(em.config.module_log_lines));
+        capture_output(em, files.index, &mut child, &logs);
+
+        Module {
+            loader,
+            files,
+            options,
+            child,
+            token,
+            logs
+        }
+    }
+
+    /// Replace the process of a module that has been killed. Its logs are kept
+    pub fn restart(&mut self, em : &EventManager, mut child : Child, token : String) {
+        capture_output(em, self.files.index, &mut child, &self.logs);
+        self.child = child;
+        self.token = token;
+    }
+
+    pub fn get_loader(&self) -> &'static str {
+        self.loader
+    }
+
+    pub fn get_files(&self) -> &ModuleFiles {
+        &self.files
+    }
+
+    pub fn get_options(&self) -> &LoadOptions {
+        &self.options
+    }
+
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn get_logs(&self) -> Arc<ModuleLogs> {
+        self.logs.clone()
+    }
+
+    /// Exit status of the module, if it has terminated
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        self.child.try_wait().unwrap_or(None)
+    }
+
+    pub fn kill(&mut self) {
+        if self.child.kill().is_err() {
+            warn!("Failed to kill module with PID {}", self.child.id());
+        }
+
+        // reap the process
+        let _ = self.child.wait();
+    }
+}
+
+
+/// A new random token for a module process
+pub fn new_token() -> io::Result<String> {
+    let mut token = [0u8; TOKEN_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut token)?;
+    Ok(hex::encode(token))
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogStream {
+    Stdout = 1,
+    Stderr = 2
+}
+
+#[derive(Clone)]
+pub struct LogLine {
+    pub stream : LogStream,
+    pub line : String
+}
+
+struct LogBuffer {
+    lines : VecDeque<LogLine>,
+    // sequence number of lines[0]
+    first_seq : u64,
+    open_streams : u8
+}
+
+/// Bounded buffer with the last lines written by a module
+pub struct ModuleLogs {
+    capacity : usize,
+    buffer : Mutex<LogBuffer>,
+    cond : Condvar
+}
+
+impl ModuleLogs {
+    fn new(capacity : usize) -> ModuleLogs {
+        ModuleLogs {
+            capacity,
+            buffer : Mutex::new(LogBuffer {
+                lines : VecDeque::with_capacity(capacity),
+                first_seq : 0,
+                open_streams : 0
+            }),
+            cond : Condvar::new()
+        }
+    }
+
+    fn open_streams(&self, n : u8) {
+        self.buffer.lock().unwrap().open_streams += n;
+    }
+
+    fn push(&self, line : LogLine) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        // with a capacity of 0, the line is dropped right away
+        buffer.lines.push_back(line);
+        if buffer.lines.len() > self.capacity {
+            buffer.lines.pop_front();
+            buffer.first_seq += 1;
+        }
+
+        self.cond.notify_all();
+    }
+
+    fn close_stream(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.open_streams = buffer.open_streams.saturating_sub(1);
+        self.cond.notify_all();
+    }
+
+    /// Last `n` lines, and the sequence number of the next line
+    pub fn tail(&self, n : usize) -> (Vec<LogLine>, u64) {
+        let buffer = self.buffer.lock().unwrap();
+        let skip = buffer.lines.len().saturating_sub(n);
+        let next = buffer.first_seq + buffer.lines.len() as u64;
+
+        (buffer.lines.iter().skip(skip).cloned().collect(), next)
+    }
+
+    /// Wait up to `timeout` until the module closes its output
+    pub fn wait_closed(&self, timeout : Duration) {
+        let buffer = self.buffer.lock().unwrap();
+        let _ = self.cond.wait_timeout_while(buffer, timeout, |b| b.open_streams > 0).unwrap();
+    }
+
+    /// Lines starting from sequence number `seq`, waiting up to `timeout` if there are none
+    ///
+    /// Returns the lines, the sequence number of the next line and whether the module
+    /// closed its output
+    pub fn wait_from(&self, seq : u64, timeout : Duration) -> (Vec<LogLine>, u64, bool) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let next = buffer.first_seq + buffer.lines.len() as u64;
+
+        if seq >= next && buffer.open_streams > 0 {
+            buffer = self.cond.wait_timeout(buffer, timeout).unwrap().0;
+        }
+
+        // lines older than first_seq have been dropped in the meantime
+        let skip = seq.saturating_sub(buffer.first_seq) as usize;
+        let next = buffer.first_seq + buffer.lines.len() as u64;
+
+        (buffer.lines.iter().skip(skip).cloned().collect(), next, buffer.open_streams == 0)
+    }
+}
+
+
+/// Encode log lines as `[<stream (u8)><len (u16)><line>]` entries, split in messages that
+/// fit in a single `reactive_net` message
+pub fn encode_log_lines(lines : &[LogLine]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut current = Vec::new();
+
+    for line in lines {
+        let data = line.line.as_bytes();
+
+        if current.len() + data.len() + 3 > u16::MAX as usize {
+            messages.push(current);
+            current = Vec::new();
+        }
+
+        push_tlv(&mut current, line.stream as u8, data);
+    }
+
+    if !current.is_empty() {
+        messages.push(current);
+    }
+
+    messages
+}
+
+
+fn capture_output(em : &EventManager, index : u16, child : &mut Child, logs : &Arc<ModuleLogs>) {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let forward = em.config.module_log_forward;
+
+    // streams of a previous process may still be open, until they are drained
+    logs.open_streams(stdout.is_some() as u8 + stderr.is_some() as u8);
+
+    if let Some(stdout) = stdout {
+        capture_stream(index, LogStream::Stdout, stdout, logs.clone(), forward);
+    }
+    if let Some(stderr) = stderr {
+        capture_stream(index, LogStream::Stderr, stderr, logs.clone(), forward);
+    }
+}
+
+fn capture_stream<R : Read + Send + 'static>(index : u16, stream : LogStream, output : R,
+        logs : Arc<ModuleLogs>, forward : bool) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+            match (&mut reader).take(MAX_LINE_LEN as u64).read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_)  => break,
+                Ok(_)           => ()
+            }
+
+            match buf.ends_with(b"\n") {
+                true    => { buf.pop(); },
+                // the line is too long: drop the rest of it
+                false   => if buf.len() == MAX_LINE_LEN && skip_line(&mut reader).is_err() {
+                    break;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf).into_owned();
+
+            if forward {
+                info!("[sm{} {:?}] {}", index, stream, line);
+            }
+
+            logs.push(LogLine { stream, line });
+        }
+
+        logs.close_stream();
+    });
+}
+
+/// Drop the rest of a line, without keeping it in memory
+fn skip_line<R : BufRead>(reader : &mut R) -> io::Result<()> {
+    let mut rest = Vec::new();
+
+    loop {
+        rest.clear();
+        match reader.by_ref().take(MAX_LINE_LEN as u64).read_until(b'\n', &mut rest)? {
+            0                           => return Ok(()),
+            _ if rest.ends_with(b"\n")  => return Ok(()),
+            _                           => ()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn long_lines() {
+        let mut output = vec![b'a'; MAX_LINE_LEN * 3];
+        output.extend_from_slice(b"\nnext\n");
+        output.extend_from_slice(&vec![b'b'; MAX_LINE_LEN]);
+        output.extend_from_slice(b"\nlast");
+
+        let logs = Arc::new(ModuleLogs::new(10));
+        logs.open_streams(1);
+        capture_stream(1, LogStream::Stdout, Cursor::new(output), logs.clone(), false);
+        logs.wait_closed(Duration::from_secs(5));
+
+        let lines : Vec<String> = logs.tail(10).0.into_iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec!["a".repeat(MAX_LINE_LEN), "next".to_string(),
+            "b".repeat(MAX_LINE_LEN), "last".to_string()]);
+    }
+
+    #[test]
+    fn capacity() {
+        let line = |s : &str| LogLine { stream : LogStream::Stdout, line : s.to_string() };
+
+        let logs = ModuleLogs::new(2);
+        for s in ["a", "b", "c"] {
+            logs.push(line(s));
+        }
+        let (lines, next) = logs.tail(10);
+        assert_eq!(lines.iter().map(|l| l.line.as_str()).collect::<Vec<_>>(), vec!["b", "c"]);
+        assert_eq!(next, 3);
+
+        // no lines are kept, but they are still counted
+        let logs = ModuleLogs::new(0);
+        logs.push(line("a"));
+        logs.push(line("b"));
+        let (lines, next) = logs.tail(10);
+        assert!(lines.is_empty());
+        assert_eq!(next, 2);
+    }
+}
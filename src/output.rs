@@ -1,79 +1,220 @@
-use std::net::TcpStream;
+use std::net::{TcpStream, Ipv4Addr};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use crate::connection::{Connection, Destinations};
+use crate::endpoint::{Endpoint, get_endpoint};
+use crate::protocol::*;
+use crate::capabilities::{peer_capabilities, forget_peer};
+use crate::commands::ExtCommandCode;
+use crate::queue;
+use crate::EventManager;
+
+use reactive_net::{CommandCode, Error, EntrypointID};
+
+use log::{debug, warn};
+
+
+/// Send an output to the destinations of its connection: to all of them in parallel, or to
+/// one replica. Returns one result per destination that was used. With `ordered`, the
+/// output has been delivered when this returns, also to other EMs
+pub fn deliver_output(em : &EventManager, output : &ModuleOutput, destinations : &Destinations,
+        ack : bool, ordered : bool) -> Vec<Result<Option<ResultMessage>, CommandError>> {
+    // replicas are only tried in turn if the result of the previous one is known
+    let wait = ack || destinations.policy != DeliveryPolicy::FanOut;
+
+    let deliver = &|conn : Connection| match conn.is_local_connection() {
+        true    => handle_local_connection(em, output, conn, wait),
+        false   => handle_remote_connection(em, output, conn, ack, ordered)
+    };
+
+    if destinations.policy != DeliveryPolicy::FanOut {
+        return vec![deliver_to_replica(output, destinations, deliver)];
+    }
+
+    // no need for a thread if there is a single destination
+    let all = destinations.all();
+    if all.len() == 1 {
+        return vec![deliver(all[0].conn.clone())];
+    }
+
+    thread::scope(|s| {
+        let handles : Vec<_> = all.iter()
+            .map(|d| s.spawn(move || deliver(d.conn.clone())))
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
 
-use crate::connection::Connection;
+/// Try the replicas in the order of the policy, until one of them can be reached
+fn deliver_to_replica<F>(output : &ModuleOutput, destinations : &Destinations, deliver : F)
+        -> Result<Option<ResultMessage>, CommandError>
+        where F : Fn(Connection) -> Result<Option<ResultMessage>, CommandError> {
+    let mut error = CommandError::UnknownConnection(output.conn_id);
+
+    for dest in destinations.candidates() {
+        let _outstanding = dest.start_delivery();
+
+        match deliver(dest.conn.clone()) {
+            Err(e) if is_unreachable(&e)    => {
+                warn!("Connection {}: replica {:?} is down: {}", output.conn_id, dest.conn, e);
+                dest.mark_down();
+                error = e;
+            },
+            res                             => {
+                dest.mark_up();
+                return res;
+            }
+        }
+    }
 
-use reactive_net::{ResultMessage, CommandCode, CommandMessage, Error, EntrypointID};
+    Err(error)
+}
 
-use log::debug;
+
+/// Whether another replica should be tried after `e`
+fn is_unreachable(e : &CommandError) -> bool {
+    matches!(e, CommandError::UnknownModule(_) | CommandError::ModuleUnavailable(_) |
+        CommandError::ModuleExited(_, _) | CommandError::PeerUnreachable(_) |
+        CommandError::Timeout(_))
+}
 
 
-pub fn handle_local_connection(payload : Vec<u8>, conn : Connection)
-        -> Result<Option<ResultMessage>, Error>{
+/// Send an output to a module of this EM. Events go through the queue of the module: without
+/// `wait`, the result is `Ok` once the event is queued, before the module has received it
+pub fn handle_local_connection(em : &EventManager, output : &ModuleOutput, conn : Connection,
+        wait : bool) -> Result<Option<ResultMessage>, CommandError> {
     debug!("Handling local connection");
 
     let to_sm = conn.get_sm();
     debug!("To SM: {}", to_sm);
 
-    match connect_to_sm(to_sm, &payload) {
-        Ok(res)     => Ok(Some(res)),
-        Err(e)      => Err(e)
+    match EntrypointID::from_u16(output.entry) {
+        EntrypointID::HandleInput   => {
+            let res = queue::deliver(em, to_sm, output.conn_id, output.entry,
+                output.sm_payload(), wait)?;
+
+            // the output is queued, errors of its delivery become dead letters
+            Ok(res.or_else(|| Some(ResultMessage::new(ResultCode::Ok, None))))
+        },
+        _                           => connect_to_sm(em, to_sm, &output.sm_payload()).map(Some)
     }
 }
 
 
-pub fn handle_remote_connection(mut payload : Vec<u8>, conn_id : u16, entry_id : u16,
-            conn : Connection) -> Result<Option<ResultMessage>, Error> {
+/// Send an output to the EM of its connection. With `ack`, outputs for HandleInput also
+/// get a result, if the remote EM supports RemoteOutputAck. With `ordered`, the remote EM
+/// is also asked to acknowledge them, so that the next output cannot overtake this one
+pub fn handle_remote_connection(em : &EventManager, output : &ModuleOutput,
+        conn : Connection, ack : bool, ordered : bool)
+        -> Result<Option<ResultMessage>, CommandError> {
     debug!("Handling remote connection");
-    debug!("Connection ID: {}", conn_id);
-
-    let sm_id = conn.get_sm().to_be_bytes();
-    // replace entry ID with sm ID
-    payload[0] = sm_id[0];
-    payload[1] = sm_id[1];
+    debug!("Connection ID: {}", output.conn_id);
+
+    // the remote EM needs the SM ID instead of the entry ID
+    let payload = RemoteOutput {
+        module : conn.get_sm(),
+        conn_id : output.conn_id,
+        data : output.data.clone()
+    }.encode();
+
+    match EntrypointID::from_u16(output.entry) {
+        EntrypointID::HandleInput if ack || ordered => {
+            let res = match connect_to_em(em, conn.clone(),
+                    ExtCommandCode::RemoteOutputAck as u8, &payload, true) {
+                // older EMs can only tell that the output was sent
+                Err(CommandError::Unsupported(_, _))    =>
+                    connect_to_em(em, conn, CommandCode::RemoteOutput as u8, &payload, false)
+                    .map(|_| Some(ResultMessage::new(ResultCode::Ok, None))),
+                res                                     => res
+            };
+
+            // without `ack`, the module does not expect a result
+            res.map(|r| r.filter(|_| ack))
+        },
+        EntrypointID::HandleInput   =>
+            connect_to_em(em, conn, CommandCode::RemoteOutput as u8, &payload, false),
+        EntrypointID::HandleHandler =>
+            connect_to_em(em, conn, CommandCode::RemoteRequest as u8, &payload, true),
+        _                           => Err(CommandError::Malformed(
+            format!("Invalid entry point for an output: {}", output.entry)))
+    }
+}
 
 
-    match EntrypointID::from_u16(entry_id) {
-        EntrypointID::HandleInput   => {
-            let cmd = CommandMessage::new(CommandCode::RemoteOutput, Some(payload));
-            connect_to_em(conn, cmd, false)
-        }
-        EntrypointID::HandleHandler => {
-            let cmd = CommandMessage::new(CommandCode::RemoteRequest, Some(payload));
-            connect_to_em(conn, cmd, true)
+pub fn connect_to_sm(em : &EventManager, sm_id : u16, data : &[u8])
+        -> Result<ResultMessage, CommandError> {
+    match get_endpoint(em, sm_id) {
+        Some(endpoint)  => send_to_endpoint(sm_id, &endpoint, data),
+        None            => {
+            debug!("No endpoint for SM {}", sm_id);
+            Err(CommandError::UnknownModule(sm_id))
         }
-        _                           => Err(Error::InvalidPayload)
     }
 }
 
 
-pub fn connect_to_sm(sm_id : u16, data : &[u8]) -> Result<ResultMessage, Error> {
-    let addr = format!("127.0.0.1:{}", *crate::PORT + sm_id);
+/// Send a message to module `sm_id` at `endpoint`, returns its result
+pub fn send_to_endpoint(sm_id : u16, endpoint : &Endpoint, data : &[u8])
+        -> Result<ResultMessage, CommandError> {
+    if data.len() > u16::MAX as usize {
+        return Err(CommandError::PayloadTooLarge(format!("{} bytes", data.len())));
+    }
 
-    let mut stream = match TcpStream::connect(addr) {
-        Ok(s) => s,
-        Err(_) => return Err(Error::NetworkError)
-    };
+    let unavailable = |_ : Error| CommandError::ModuleUnavailable(sm_id);
+
+    let result = match endpoint {
+        Endpoint::Tcp(port)     => {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, *port))
+                .map_err(|_| CommandError::ModuleUnavailable(sm_id))?;
+
+            write_message_to(&mut stream, data).map_err(unavailable)?;
+            read_result_from(&mut stream).map_err(unavailable)?
+        },
+        Endpoint::Unix(path)    => {
+            let mut stream = UnixStream::connect(path)
+                .map_err(|_| CommandError::ModuleUnavailable(sm_id))?;
 
-    reactive_net::write_message(&mut stream, data)?;
-    let result = reactive_net::read_result(&mut stream)?;
+            write_message_to(&mut stream, data).map_err(unavailable)?;
+            read_result_from(&mut stream).map_err(unavailable)?
+        }
+    };
 
     debug!("Response from SM: {:?}", result);
     Ok(result)
 }
 
 
-pub fn connect_to_em(conn : Connection, cmd : CommandMessage, has_resp : bool)
-    -> Result<Option<ResultMessage>, Error> {
-    let mut stream = match TcpStream::connect(conn.get_address()) {
+pub fn connect_to_em(em : &EventManager, conn : Connection, code : u8, payload : &[u8],
+        has_resp : bool) -> Result<Option<ResultMessage>, CommandError> {
+    let addr = conn.get_address();
+    let unreachable = |_ : Error| CommandError::PeerUnreachable(addr);
+
+    // do not send commands the remote EM does not understand
+    if !peer_capabilities(em, addr).map_err(unreachable)?.supports(code) {
+        return Err(CommandError::Unsupported(addr, code));
+    }
+
+    if payload.len() > u16::MAX as usize {
+        return Err(CommandError::PayloadTooLarge(format!("{} bytes", payload.len())));
+    }
+
+    let mut stream = match TcpStream::connect(addr) {
         Ok(s) => s,
-        Err(_) => return Err(Error::NetworkError)
+        Err(_) => {
+            // the EM could come back with a different version
+            forget_peer(em, addr);
+            return Err(CommandError::PeerUnreachable(addr));
+        }
     };
 
-    reactive_net::write_command(&mut stream, &cmd)?;
+    write_raw_command(&mut stream, code, payload).map_err(|_| CommandError::PeerUnreachable(addr))?;
 
     match has_resp {
         true    => {
-            let result = reactive_net::read_result(&mut stream)?;
+            let result = read_result_from(&mut stream).map_err(unreachable)?;
             Ok(Some(result))
         }
         false   => Ok(None)
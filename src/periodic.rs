@@ -1,9 +1,11 @@
-use std::net::TcpStream;
 use std::{thread, time};
+use std::sync::Arc;
 use log::{warn};
-use reactive_net::{CommandMessage, CommandCode};
 
-use crate::{PERIODIC_TASKS, PORT};
+use crate::EventManager;
+use crate::dispatch::Lane;
+use crate::output::connect_to_sm;
+use crate::protocol::{entrypoint_payload, Priority};
 
 const BASE_FREQUENCY : u32 = 50;
 
@@ -12,16 +14,19 @@ pub struct PeriodicTask {
     module : u16,
     entry : u16,
     frequency : u32,
-    counter : u32
+    counter : u32,
+    priority : Priority
 }
 
 impl PeriodicTask {
-    pub fn new(module : u16, entry : u16, frequency : u32) -> PeriodicTask {
+    pub fn new(module : u16, entry : u16, frequency : u32, priority : Priority)
+            -> PeriodicTask {
         PeriodicTask {
             module,
             entry,
             frequency : set_frequency(frequency),
-            counter : 0u32
+            counter : 0u32,
+            priority
         }
     }
 
@@ -55,18 +60,19 @@ fn set_frequency(freq : u32) -> u32 {
     }
 }
 
-pub fn run_periodic_tasks() {
+/// Call the entry points of the tasks when they are due, on the workers of `lane`
+pub fn run_periodic_tasks(em : Arc<EventManager>, lane : Arc<Lane>) {
     loop {
         // Phase 1: scan vector to update counters and check which are the entry to call now
         let mut local_tasks : Vec<PeriodicTask> = Vec::new();
 
-        let mut tasks = PERIODIC_TASKS.lock().unwrap();
+        let mut tasks = em.periodic_tasks.lock().unwrap();
 
         for task in &mut *tasks {
             let to_call = task.increment_counter();
 
             if to_call {
-                local_tasks.push(task.clone());
+                local_tasks.push(*task);
             }
         }
 
@@ -77,26 +83,15 @@ pub fn run_periodic_tasks() {
             let module = task.get_module();
             let entry = task.get_entry();
 
-            let mut payload = Vec::with_capacity(4);
-            payload.extend_from_slice(&module.to_be_bytes());
-            payload.extend_from_slice(&entry.to_be_bytes());
-
-            let addr = format!("127.0.0.1:{}", *PORT);
-            let mut stream = match TcpStream::connect(addr) {
-                Ok(s) => s,
-                Err(_) => {
-                    warn!("Cannot connect to EM");
-                    return;
+            // call the module from a worker, to not delay the other tasks
+            let em = em.clone();
+            lane.execute(task.priority, move || {
+                if let Err(e) = connect_to_sm(&em, module, &entrypoint_payload(entry, &[])) {
+                    warn!("Periodic task {}:{} failed: {}", module, entry, e);
                 }
-            };
-
-            let cmd = CommandMessage::new(CommandCode::CallEntrypoint, Some(payload));
-
-            if let Err(e) = reactive_net::write_command(&mut stream, &cmd) {
-                warn!("{}", e);
-            }
 
-            // i don't care about the response (TODO?)
+                // i don't care about the response (TODO?)
+            });
         }
 
         // Phase 3: go to sleep and repeat
@@ -0,0 +1,1673 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::convert::TryInto;
+
+use reactive_net::Error;
+
+use crate::helpers::{push_tlv, parse_tlv};
+
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// the payload has a length that is not valid for the command
+    Length(usize),
+    Invalid(String)
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Length(len)    => write!(f, "Payload length is not correct: {}", len),
+            DecodeError::Invalid(msg)   => write!(f, "Invalid payload: {}", msg)
+        }
+    }
+}
+
+
+pub fn bytes_to_u16(buf : &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+
+pub fn bytes_to_u32(buf : &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+
+pub fn bytes_to_u64(buf : &[u8]) -> u64 {
+    u64::from_be_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]])
+}
+
+
+/// Result codes. Codes up to `GenericError` have the same values as
+/// `reactive_net::ResultCode`, the others are only sent for commands with
+/// `ERROR_CODES_FLAG` set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultCode {
+    Ok = 0,
+    IllegalCommand = 1,
+    IllegalPayload = 2,
+    InternalError = 3,
+    BadRequest = 4,
+    CryptoError = 5,
+    GenericError = 6,
+    UnknownConnection = 7,
+    UnknownModule = 8,
+    ModuleUnavailable = 9,
+    PeerUnreachable = 10,
+    Unsupported = 11,
+    Timeout = 12,
+    PayloadTooLarge = 13,
+    Unauthorized = 14,
+    Rejected = 15,
+    NotFound = 16,
+    QueueFull = 17
+}
+
+impl ResultCode {
+    pub fn from_u8(code : u8) -> Option<ResultCode> {
+        match code {
+            0   => Some(ResultCode::Ok),
+            1   => Some(ResultCode::IllegalCommand),
+            2   => Some(ResultCode::IllegalPayload),
+            3   => Some(ResultCode::InternalError),
+            4   => Some(ResultCode::BadRequest),
+            5   => Some(ResultCode::CryptoError),
+            6   => Some(ResultCode::GenericError),
+            7   => Some(ResultCode::UnknownConnection),
+            8   => Some(ResultCode::UnknownModule),
+            9   => Some(ResultCode::ModuleUnavailable),
+            10  => Some(ResultCode::PeerUnreachable),
+            11  => Some(ResultCode::Unsupported),
+            12  => Some(ResultCode::Timeout),
+            13  => Some(ResultCode::PayloadTooLarge),
+            14  => Some(ResultCode::Unauthorized),
+            15  => Some(ResultCode::Rejected),
+            16  => Some(ResultCode::NotFound),
+            17  => Some(ResultCode::QueueFull),
+            _   => None
+        }
+    }
+
+    /// Closest code known by callers that do not set `ERROR_CODES_FLAG`
+    pub fn legacy(self) -> ResultCode {
+        match self {
+            ResultCode::PayloadTooLarge     => ResultCode::IllegalPayload,
+            ResultCode::UnknownConnection |
+            ResultCode::UnknownModule |
+            ResultCode::Unauthorized |
+            ResultCode::Rejected |
+            ResultCode::NotFound            => ResultCode::BadRequest,
+            ResultCode::ModuleUnavailable |
+            ResultCode::PeerUnreachable |
+            ResultCode::Unsupported |
+            ResultCode::Timeout |
+            ResultCode::QueueFull           => ResultCode::GenericError,
+            code                            => code
+        }
+    }
+}
+
+
+/// Result of a command: `[<code (u8)><len (u16)><payload>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResultMessage {
+    code : ResultCode,
+    payload : Option<Vec<u8>>
+}
+
+impl ResultMessage {
+    pub fn new(code : ResultCode, payload : Option<Vec<u8>>) -> ResultMessage {
+        ResultMessage {
+            code,
+            payload
+        }
+    }
+
+    pub fn get_code(&self) -> ResultCode {
+        self.code
+    }
+
+    pub fn get_payload(&self) -> Option<&[u8]> {
+        self.payload.as_deref()
+    }
+
+    /// The same result, with a code known by callers that do not set `ERROR_CODES_FLAG`
+    pub fn legacy(self) -> ResultMessage {
+        ResultMessage::new(self.code.legacy(), self.payload)
+    }
+}
+
+
+/// Why a command failed. It is sent to the caller as a specific result code, with a
+/// human-readable description as payload
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    /// the command code is unknown, or not allowed here
+    IllegalCommand(u8),
+    /// the message could not be read, or its payload is not valid for the command
+    Malformed(String),
+    /// a payload does not fit in a message, or an upload exceeds its limit
+    PayloadTooLarge(String),
+    UnknownConnection(u16),
+    /// no module is loaded or registered with this ID (or index)
+    UnknownModule(u16),
+    /// the module is known, but the EM cannot talk to it
+    ModuleUnavailable(u16),
+    /// the module exited, with a diagnostic
+    ModuleExited(u16, String),
+    PeerUnreachable(SocketAddrV4),
+    /// the remote EM does not support the command
+    Unsupported(SocketAddrV4, u8),
+    Timeout(String),
+    Unauthorized(String),
+    /// the module must not be launched (e.g. it does not pass a policy check)
+    Rejected(String),
+    /// unknown loader, artifact or upload
+    NotFound(String),
+    /// the queue of the module is full, and the output was rejected or dropped
+    QueueFull(u16),
+    BadRequest(String),
+    Internal(String)
+}
+
+impl CommandError {
+    pub fn code(&self) -> ResultCode {
+        match self {
+            CommandError::IllegalCommand(_)     => ResultCode::IllegalCommand,
+            CommandError::Malformed(_)          => ResultCode::IllegalPayload,
+            CommandError::PayloadTooLarge(_)    => ResultCode::PayloadTooLarge,
+            CommandError::UnknownConnection(_)  => ResultCode::UnknownConnection,
+            CommandError::UnknownModule(_)      => ResultCode::UnknownModule,
+            CommandError::ModuleUnavailable(_) |
+            CommandError::ModuleExited(_, _)    => ResultCode::ModuleUnavailable,
+            CommandError::PeerUnreachable(_)    => ResultCode::PeerUnreachable,
+            CommandError::Unsupported(_, _)     => ResultCode::Unsupported,
+            CommandError::Timeout(_)            => ResultCode::Timeout,
+            CommandError::Unauthorized(_)       => ResultCode::Unauthorized,
+            CommandError::Rejected(_)           => ResultCode::Rejected,
+            CommandError::NotFound(_)           => ResultCode::NotFound,
+            CommandError::QueueFull(_)          => ResultCode::QueueFull,
+            CommandError::BadRequest(_)         => ResultCode::BadRequest,
+            CommandError::Internal(_)           => ResultCode::InternalError
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommandError::IllegalCommand(code)      => write!(f, "Illegal command: {}", code),
+            CommandError::Malformed(msg)            => write!(f, "Malformed message: {}", msg),
+            CommandError::PayloadTooLarge(msg)      => write!(f, "Payload is too large: {}", msg),
+            CommandError::UnknownConnection(id)     => write!(f, "No connection with ID {}", id),
+            CommandError::UnknownModule(id)         => write!(f, "Unknown module {}", id),
+            CommandError::ModuleUnavailable(id)     => write!(f, "Module {} is unavailable", id),
+            CommandError::ModuleExited(id, diag)    => write!(f, "Module {} exited: {}", id, diag),
+            CommandError::PeerUnreachable(addr)     => write!(f, "EM {} is unreachable", addr),
+            CommandError::Unsupported(addr, code)   =>
+                write!(f, "EM {} does not support command {}", addr, code),
+            CommandError::Timeout(msg)              => write!(f, "Timeout: {}", msg),
+            CommandError::Unauthorized(msg)         => write!(f, "Unauthorized: {}", msg),
+            CommandError::Rejected(msg)             => write!(f, "Rejected: {}", msg),
+            CommandError::NotFound(msg)             => write!(f, "Not found: {}", msg),
+            CommandError::QueueFull(id)             => write!(f, "Queue of module {} is full", id),
+            CommandError::BadRequest(msg)           => write!(f, "Bad request: {}", msg),
+            CommandError::Internal(msg)             => write!(f, "Internal error: {}", msg)
+        }
+    }
+}
+
+impl From<DecodeError> for CommandError {
+    fn from(e : DecodeError) -> CommandError {
+        CommandError::Malformed(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for CommandError {
+    fn from(e : std::io::Error) -> CommandError {
+        match e.kind() {
+            std::io::ErrorKind::NotFound        => CommandError::NotFound(e.to_string()),
+            std::io::ErrorKind::UnexpectedEof   => CommandError::Malformed(e.to_string()),
+            std::io::ErrorKind::TimedOut |
+            std::io::ErrorKind::WouldBlock      => CommandError::Timeout(e.to_string()),
+            _                                   => CommandError::Internal(e.to_string())
+        }
+    }
+}
+
+impl From<CommandError> for ResultMessage {
+    fn from(e : CommandError) -> ResultMessage {
+        ResultMessage::new(e.code(), Some(e.to_string().into_bytes()))
+    }
+}
+
+
+/// A connection to the EM
+///
+/// Handlers only need to read and write, so that they can also be driven by an in-memory
+/// stream (e.g. when fuzzing)
+pub trait Stream : Read + Write {
+    /// Address of the peer, if it is connected over the network
+    fn peer_address(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+
+/// Payload of a command or of a result
+pub trait Payload : Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(data : &[u8]) -> Result<Self, DecodeError>;
+}
+
+
+/// Payload sent to a module to call one of its entry points: `[<entry (u16)><data>]`
+pub fn entrypoint_payload(entry : u16, data : &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.extend_from_slice(&entry.to_be_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+
+/// AddConnection and RemoveConnection, which add or remove a destination of a connection:
+/// `[<conn ID (u16)><to SM (u16)><local (u8)><EM port (u16)><EM address (4 bytes)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddConnection {
+    pub conn_id : u16,
+    pub to_sm : u16,
+    pub local : bool,
+    pub em_port : u16,
+    pub em_address : Ipv4Addr
+}
+
+pub type RemoveConnection = AddConnection;
+
+impl Payload for AddConnection {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(11);
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.extend_from_slice(&self.to_sm.to_be_bytes());
+        buf.push(self.local as u8);
+        buf.extend_from_slice(&self.em_port.to_be_bytes());
+        buf.extend_from_slice(&self.em_address.octets());
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<AddConnection, DecodeError> {
+        if data.len() != 11 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(AddConnection {
+            conn_id : bytes_to_u16(&data[..2]),
+            to_sm : bytes_to_u16(&data[2..4]),
+            local : data[4] != 0,
+            em_port : bytes_to_u16(&data[5..7]),
+            em_address : Ipv4Addr::new(data[7], data[8], data[9], data[10])
+        })
+    }
+}
+
+
+/// How an output is delivered to the destinations of its connection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DeliveryPolicy {
+    /// to all of them
+    #[default]
+    FanOut = 0,
+    /// to one of them, taking turns
+    RoundRobin = 1,
+    /// to the one with the fewest outputs being delivered
+    LeastOutstanding = 2,
+    /// to the first one that was added, the others are backups
+    PrimaryBackup = 3
+}
+
+impl DeliveryPolicy {
+    pub fn from_u8(policy : u8) -> Option<DeliveryPolicy> {
+        match policy {
+            0   => Some(DeliveryPolicy::FanOut),
+            1   => Some(DeliveryPolicy::RoundRobin),
+            2   => Some(DeliveryPolicy::LeastOutstanding),
+            3   => Some(DeliveryPolicy::PrimaryBackup),
+            _   => None
+        }
+    }
+}
+
+
+/// SetConnectionPolicy: `[<conn ID (u16)><policy (u8)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetConnectionPolicy {
+    pub conn_id : u16,
+    pub policy : DeliveryPolicy
+}
+
+impl Payload for SetConnectionPolicy {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.conn_id.to_be_bytes().to_vec();
+        buf.push(self.policy as u8);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<SetConnectionPolicy, DecodeError> {
+        if data.len() != 3 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        let policy = DeliveryPolicy::from_u8(data[2]).ok_or_else(||
+            DecodeError::Invalid(format!("Unknown delivery policy: {}", data[2])))?;
+
+        Ok(SetConnectionPolicy { conn_id : bytes_to_u16(&data[..2]), policy })
+    }
+}
+
+
+/// Whether the outputs of a connection are delivered in the order they are received
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConnectionOrdering {
+    /// in parallel, outputs can overtake each other
+    #[default]
+    Unordered = 0,
+    /// one at a time, in the order they are received
+    Fifo = 1
+}
+
+impl ConnectionOrdering {
+    pub fn from_u8(ordering : u8) -> Option<ConnectionOrdering> {
+        match ordering {
+            0   => Some(ConnectionOrdering::Unordered),
+            1   => Some(ConnectionOrdering::Fifo),
+            _   => None
+        }
+    }
+}
+
+
+/// SetConnectionOrdering: `[<conn ID (u16)><ordering (u8)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetConnectionOrdering {
+    pub conn_id : u16,
+    pub ordering : ConnectionOrdering
+}
+
+impl Payload for SetConnectionOrdering {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.conn_id.to_be_bytes().to_vec();
+        buf.push(self.ordering as u8);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<SetConnectionOrdering, DecodeError> {
+        if data.len() != 3 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        let ordering = ConnectionOrdering::from_u8(data[2]).ok_or_else(||
+            DecodeError::Invalid(format!("Unknown ordering: {}", data[2])))?;
+
+        Ok(SetConnectionOrdering { conn_id : bytes_to_u16(&data[..2]), ordering })
+    }
+}
+
+/// Priority of the events of a connection or of a periodic task. Events with a higher
+/// priority are handled first when they wait for a worker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// e.g. bulk telemetry
+    Low = 0,
+    #[default]
+    Normal = 1,
+    /// e.g. time-critical actuator events
+    High = 2
+}
+
+impl Priority {
+    pub fn from_u8(priority : u8) -> Option<Priority> {
+        match priority {
+            0   => Some(Priority::Low),
+            1   => Some(Priority::Normal),
+            2   => Some(Priority::High),
+            _   => None
+        }
+    }
+}
+
+
+/// SetConnectionPriority: `[<conn ID (u16)><priority (u8)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetConnectionPriority {
+    pub conn_id : u16,
+    pub priority : Priority
+}
+
+impl Payload for SetConnectionPriority {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.conn_id.to_be_bytes().to_vec();
+        buf.push(self.priority as u8);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<SetConnectionPriority, DecodeError> {
+        if data.len() != 3 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        let priority = Priority::from_u8(data[2]).ok_or_else(||
+            DecodeError::Invalid(format!("Unknown priority: {}", data[2])))?;
+
+        Ok(SetConnectionPriority { conn_id : bytes_to_u16(&data[..2]), priority })
+    }
+}
+
+
+/// CallEntrypoint: `[<SM (u16)><entry (u16)><data>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallEntrypoint {
+    pub module : u16,
+    pub entry : u16,
+    pub data : Vec<u8>
+}
+
+impl CallEntrypoint {
+    /// What is sent to the module
+    pub fn sm_payload(&self) -> Vec<u8> {
+        entrypoint_payload(self.entry, &self.data)
+    }
+}
+
+impl Payload for CallEntrypoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() + 4);
+        buf.extend_from_slice(&self.module.to_be_bytes());
+        buf.extend_from_slice(&self.entry.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<CallEntrypoint, DecodeError> {
+        if data.len() < 4 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(CallEntrypoint {
+            module : bytes_to_u16(&data[..2]),
+            entry : bytes_to_u16(&data[2..4]),
+            data : data[4..].to_vec()
+        })
+    }
+}
+
+
+/// LoadSM: `[<size (u32)><data>]` for each file of the loader
+///
+/// Unlike the other commands, the payload is not preceded by its length
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadSM {
+    pub files : Vec<Vec<u8>>
+}
+
+impl Payload for LoadSM {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for file in &self.files {
+            buf.extend_from_slice(&(file.len() as u32).to_be_bytes());
+            buf.extend_from_slice(file);
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<LoadSM, DecodeError> {
+        let mut files = Vec::new();
+        let mut i = 0;
+
+        while i < data.len() {
+            if data.len() - i < 4 {
+                return Err(DecodeError::Invalid("Truncated file size".to_string()));
+            }
+
+            let size = bytes_to_u32(&data[i..i + 4]) as usize;
+            i += 4;
+
+            if data.len() - i < size {
+                return Err(DecodeError::Invalid(format!("Truncated file {}", files.len())));
+            }
+
+            files.push(data[i..i + size].to_vec());
+            i += size;
+        }
+
+        Ok(LoadSM { files })
+    }
+}
+
+
+/// Result of LoadSM: `[<index (u16)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadSMResult {
+    pub index : u16
+}
+
+impl Payload for LoadSMResult {
+    fn encode(&self) -> Vec<u8> {
+        self.index.to_be_bytes().to_vec()
+    }
+
+    fn decode(data : &[u8]) -> Result<LoadSMResult, DecodeError> {
+        match data.len() {
+            2   => Ok(LoadSMResult { index : bytes_to_u16(data) }),
+            len => Err(DecodeError::Length(len))
+        }
+    }
+}
+
+
+/// Reset: empty
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reset;
+
+impl Payload for Reset {
+    fn encode(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn decode(data : &[u8]) -> Result<Reset, DecodeError> {
+        match data.len() {
+            0   => Ok(Reset),
+            len => Err(DecodeError::Length(len))
+        }
+    }
+}
+
+
+/// RegisterEntrypoint: `[<SM (u16)><entry (u16)><frequency (u32)><priority (u8)>]`, the
+/// priority being optional
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterEntrypoint {
+    pub module : u16,
+    pub entry : u16,
+    pub frequency : u32,
+    pub priority : Priority
+}
+
+impl Payload for RegisterEntrypoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(9);
+        buf.extend_from_slice(&self.module.to_be_bytes());
+        buf.extend_from_slice(&self.entry.to_be_bytes());
+        buf.extend_from_slice(&self.frequency.to_be_bytes());
+
+        // EMs without the "priorities" feature only accept the first 8 bytes
+        if self.priority != Priority::Normal {
+            buf.push(self.priority as u8);
+        }
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<RegisterEntrypoint, DecodeError> {
+        let priority = match data.len() {
+            8   => Priority::Normal,
+            9   => Priority::from_u8(data[8]).ok_or_else(||
+                DecodeError::Invalid(format!("Unknown priority: {}", data[8])))?,
+            len => return Err(DecodeError::Length(len))
+        };
+
+        Ok(RegisterEntrypoint {
+            module : bytes_to_u16(&data[..2]),
+            entry : bytes_to_u16(&data[2..4]),
+            frequency : bytes_to_u32(&data[4..8]),
+            priority
+        })
+    }
+}
+
+
+/// ModuleOutput, sent by a module: `[<entry (u16)><conn ID (u16)><data>]`
+///
+/// `entry` is the entry point of the destination module (HandleInput or HandleHandler)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleOutput {
+    pub entry : u16,
+    pub conn_id : u16,
+    pub data : Vec<u8>
+}
+
+impl ModuleOutput {
+    /// What is sent to the destination module, if it is on this EM
+    pub fn sm_payload(&self) -> Vec<u8> {
+        self.encode()
+    }
+}
+
+impl Payload for ModuleOutput {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() + 4);
+        buf.extend_from_slice(&self.entry.to_be_bytes());
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<ModuleOutput, DecodeError> {
+        if data.len() <= 4 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(ModuleOutput {
+            entry : bytes_to_u16(&data[..2]),
+            conn_id : bytes_to_u16(&data[2..4]),
+            data : data[4..].to_vec()
+        })
+    }
+}
+
+
+/// RemoteOutput and RemoteRequest, sent by another EM: `[<SM (u16)><conn ID (u16)><data>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteOutput {
+    pub module : u16,
+    pub conn_id : u16,
+    pub data : Vec<u8>
+}
+
+pub type RemoteRequest = RemoteOutput;
+
+impl RemoteOutput {
+    /// What is sent to the module, calling `entry` (HandleInput or HandleHandler)
+    pub fn sm_payload(&self, entry : u16) -> Vec<u8> {
+        ModuleOutput { entry, conn_id : self.conn_id, data : self.data.clone() }.encode()
+    }
+}
+
+impl Payload for RemoteOutput {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() + 4);
+        buf.extend_from_slice(&self.module.to_be_bytes());
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<RemoteOutput, DecodeError> {
+        if data.len() <= 6 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(RemoteOutput {
+            module : bytes_to_u16(&data[..2]),
+            conn_id : bytes_to_u16(&data[2..4]),
+            data : data[4..].to_vec()
+        })
+    }
+}
+
+
+const DL_UNDELIVERED : u8 = 0;
+const DL_EVENT : u8 = 1;
+
+/// Result of GetDeadLetters
+///
+/// Encoded as a list of `[<tag (u8)><len (u16)><value>]` entries: the number of undelivered
+/// events of each connection (`[<conn ID (u16)><count (u64)>]`), and the most recent
+/// undelivered events, newest first (`[<time (u64)><conn ID (u16)><entry (u16)><code (u8)>
+/// <message>]`, with the time in seconds since the epoch)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeadLetters {
+    pub undelivered : Vec<(u16, u64)>,
+    pub events : Vec<DeadLetter>
+}
+
+/// An output that could not be delivered
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub time : u64,
+    pub conn_id : u16,
+    pub entry : u16,
+    /// why it was not delivered
+    pub code : ResultCode,
+    pub message : String
+}
+
+impl DeadLetter {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.message.len() + 13);
+        buf.extend_from_slice(&self.time.to_be_bytes());
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.extend_from_slice(&self.entry.to_be_bytes());
+        buf.push(self.code as u8);
+        buf.extend_from_slice(self.message.as_bytes());
+        buf
+    }
+
+    /// Size of its entry in DeadLetters, header included
+    pub fn encoded_len(&self) -> usize {
+        self.message.len() + 16
+    }
+}
+
+impl Payload for DeadLetters {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for (conn_id, count) in &self.undelivered {
+            let mut value = conn_id.to_be_bytes().to_vec();
+            value.extend_from_slice(&count.to_be_bytes());
+            push_tlv(&mut buf, DL_UNDELIVERED, &value);
+        }
+        for event in &self.events {
+            push_tlv(&mut buf, DL_EVENT, &event.encode());
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<DeadLetters, DecodeError> {
+        let mut letters = DeadLetters::default();
+
+        for (tag, value) in parse_tlv(data).map_err(DecodeError::Invalid)? {
+            match tag {
+                DL_UNDELIVERED if value.len() == 10 => letters.undelivered.push(
+                    (bytes_to_u16(value), bytes_to_u64(&value[2..]))),
+                DL_EVENT if value.len() >= 13       => {
+                    let code = ResultCode::from_u8(value[12]).ok_or_else(||
+                        DecodeError::Invalid(format!("Unknown result code: {}", value[12])))?;
+
+                    letters.events.push(DeadLetter {
+                        time : bytes_to_u64(&value[..8]),
+                        conn_id : bytes_to_u16(&value[8..10]),
+                        entry : bytes_to_u16(&value[10..12]),
+                        code,
+                        message : String::from_utf8_lossy(&value[13..]).into_owned()
+                    });
+                },
+                DL_UNDELIVERED | DL_EVENT           => return Err(DecodeError::Length(value.len())),
+                // ignore what newer versions add
+                _                                   => ()
+            }
+        }
+
+        Ok(letters)
+    }
+}
+
+
+const QS_QUEUE : u8 = 0;
+
+/// Result of GetQueueStats
+///
+/// Encoded as a list of `[<tag (u8)><len (u16)><value>]` entries, one per module queue:
+/// `[<module (u16)><depth (u32)><max depth (u32)><delivered (u64)><dropped (u64)>
+/// <rejected (u64)>]`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    pub queues : Vec<QueueStat>
+}
+
+/// Queue of outputs for a module
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueStat {
+    pub module : u16,
+    /// outputs waiting to be delivered
+    pub depth : u32,
+    /// highest depth so far
+    pub max_depth : u32,
+    pub delivered : u64,
+    /// outputs dropped because the queue was full
+    pub dropped : u64,
+    /// outputs rejected because the queue was full
+    pub rejected : u64
+}
+
+impl Payload for QueueStats {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for queue in &self.queues {
+            let mut value = queue.module.to_be_bytes().to_vec();
+            value.extend_from_slice(&queue.depth.to_be_bytes());
+            value.extend_from_slice(&queue.max_depth.to_be_bytes());
+            value.extend_from_slice(&queue.delivered.to_be_bytes());
+            value.extend_from_slice(&queue.dropped.to_be_bytes());
+            value.extend_from_slice(&queue.rejected.to_be_bytes());
+            push_tlv(&mut buf, QS_QUEUE, &value);
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<QueueStats, DecodeError> {
+        let mut stats = QueueStats::default();
+
+        for (tag, value) in parse_tlv(data).map_err(DecodeError::Invalid)? {
+            match tag {
+                QS_QUEUE if value.len() == 34   => stats.queues.push(QueueStat {
+                    module : bytes_to_u16(value),
+                    depth : bytes_to_u32(&value[2..6]),
+                    max_depth : bytes_to_u32(&value[6..10]),
+                    delivered : bytes_to_u64(&value[10..18]),
+                    dropped : bytes_to_u64(&value[18..26]),
+                    rejected : bytes_to_u64(&value[26..34])
+                }),
+                QS_QUEUE                        => return Err(DecodeError::Length(value.len())),
+                // ignore what newer versions add
+                _                               => ()
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+
+/// Options of LoadModule and InstantiateSM
+///
+/// Encoded as a list of `[<tag (u8)><len (u16)><value>]` entries
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadOptions {
+    pub loader : Option<String>,
+    pub module_id : Option<u16>,
+    pub args : Vec<String>,
+    pub env : Vec<(String, String)>,
+    pub ready_timeout : Option<Duration>,
+    pub artifacts : Vec<[u8; 32]>
+}
+
+const OPTION_LOADER : u8 = 0;
+const OPTION_MODULE_ID : u8 = 1;
+const OPTION_ARG : u8 = 2;
+const OPTION_ENV : u8 = 3;
+const OPTION_WAIT_READY : u8 = 4;
+const OPTION_ARTIFACT : u8 = 5;
+
+impl Payload for LoadOptions {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        if let Some(loader) = &self.loader {
+            push_tlv(&mut buf, OPTION_LOADER, loader.as_bytes());
+        }
+        if let Some(id) = self.module_id {
+            push_tlv(&mut buf, OPTION_MODULE_ID, &id.to_be_bytes());
+        }
+        for arg in &self.args {
+            push_tlv(&mut buf, OPTION_ARG, arg.as_bytes());
+        }
+        for (key, value) in &self.env {
+            push_tlv(&mut buf, OPTION_ENV, format!("{}={}", key, value).as_bytes());
+        }
+        if let Some(timeout) = self.ready_timeout {
+            push_tlv(&mut buf, OPTION_WAIT_READY, &(timeout.as_millis() as u32).to_be_bytes());
+        }
+        for hash in &self.artifacts {
+            push_tlv(&mut buf, OPTION_ARTIFACT, hash);
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<LoadOptions, DecodeError> {
+        let mut options = LoadOptions::default();
+
+        for (tag, value) in parse_tlv(data).map_err(DecodeError::Invalid)? {
+            let string = || String::from_utf8(value.to_vec())
+                .map_err(|_| DecodeError::Invalid(format!("Option {} is not valid UTF-8", tag)));
+
+            match tag {
+                OPTION_LOADER                           => options.loader = Some(string()?),
+                OPTION_MODULE_ID if value.len() == 2    =>
+                    options.module_id = Some(bytes_to_u16(value)),
+                OPTION_ARG                              => options.args.push(string()?),
+                OPTION_ENV                              => {
+                    let var = string()?;
+                    match var.find('=') {
+                        Some(i) => options.env.push((var[..i].to_string(), var[i + 1..].to_string())),
+                        None    => return Err(DecodeError::Invalid(
+                            format!("Invalid environment variable: {}", var)))
+                    }
+                },
+                OPTION_WAIT_READY if value.len() == 4   => options.ready_timeout =
+                    Some(Duration::from_millis(bytes_to_u32(value) as u64)),
+                OPTION_ARTIFACT if value.len() == 32    =>
+                    options.artifacts.push(value.try_into().unwrap()), // should never panic
+                OPTION_MODULE_ID | OPTION_WAIT_READY |
+                OPTION_ARTIFACT                         => return Err(DecodeError::Invalid(
+                    format!("Option {} has a wrong length: {}", tag, value.len()))),
+                _                                       => return Err(DecodeError::Invalid(
+                    format!("Unknown option: {}", tag)))
+            }
+        }
+
+        // we need the module ID to know the port of the module
+        if options.ready_timeout.is_some() && options.module_id.is_none() {
+            return Err(DecodeError::Invalid("Waiting for a module requires its ID".to_string()));
+        }
+
+        Ok(options)
+    }
+}
+
+
+/// InstantiateSM: `[<index of the source module (u16)><options>]`, see `LoadOptions`
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstantiateSM {
+    pub source : u16,
+    pub options : LoadOptions
+}
+
+impl Payload for InstantiateSM {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.source.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.options.encode());
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<InstantiateSM, DecodeError> {
+        if data.len() < 2 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(InstantiateSM {
+            source : bytes_to_u16(&data[..2]),
+            options : LoadOptions::decode(&data[2..])?
+        })
+    }
+}
+
+
+/// Where a module can be reached by the EM
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// TCP port on the loopback interface
+    Tcp(u16),
+    /// Unix domain socket in the EM's directory
+    Unix(PathBuf)
+}
+
+const ENDPOINT_TCP : u8 = 0;
+const ENDPOINT_UNIX : u8 = 1;
+const METADATA_ENTRY : u8 = 0;
+const METADATA_TOKEN : u8 = 1;
+
+/// RegisterModule: `[<module ID (u16)><endpoint type (u8)><endpoint><metadata>]`
+///
+/// A TCP endpoint is a port (u16), a Unix endpoint is `[<len (u16)><path>]` and metadata is
+/// a list of `key=value` entries and of an optional token, encoded as
+/// `[<tag (u8)><len (u16)><entry>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterModule {
+    pub module_id : u16,
+    pub endpoint : Endpoint,
+    pub metadata : Vec<(String, String)>,
+    /// the `EM_MODULE_TOKEN` of the module, if the EM started it
+    pub token : Option<String>
+}
+
+impl Payload for RegisterModule {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.module_id.to_be_bytes().to_vec();
+
+        match &self.endpoint {
+            Endpoint::Tcp(port)     => {
+                buf.push(ENDPOINT_TCP);
+                buf.extend_from_slice(&port.to_be_bytes());
+            },
+            Endpoint::Unix(path)    => {
+                let path = path.to_string_lossy();
+                buf.push(ENDPOINT_UNIX);
+                buf.extend_from_slice(&(path.len() as u16).to_be_bytes());
+                buf.extend_from_slice(path.as_bytes());
+            }
+        }
+
+        for (key, value) in &self.metadata {
+            push_tlv(&mut buf, METADATA_ENTRY, format!("{}={}", key, value).as_bytes());
+        }
+        if let Some(token) = &self.token {
+            push_tlv(&mut buf, METADATA_TOKEN, token.as_bytes());
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<RegisterModule, DecodeError> {
+        if data.len() < 3 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        let (endpoint, rest) = match data[2] {
+            ENDPOINT_TCP if data.len() >= 5 => (Endpoint::Tcp(bytes_to_u16(&data[3..5])), &data[5..]),
+            ENDPOINT_TCP                    => return Err(DecodeError::Invalid("Missing port".to_string())),
+            ENDPOINT_UNIX                   => {
+                let path = &data[3..];
+                if path.len() < 2 || path.len() - 2 < bytes_to_u16(path) as usize {
+                    return Err(DecodeError::Invalid("Missing socket path".to_string()));
+                }
+
+                let len = bytes_to_u16(path) as usize;
+                match std::str::from_utf8(&path[2..2 + len]) {
+                    Ok(p)   => (Endpoint::Unix(PathBuf::from(p)), &path[2 + len..]),
+                    Err(_)  => return Err(DecodeError::Invalid(
+                        "Socket path is not valid UTF-8".to_string()))
+                }
+            },
+            t                               => return Err(DecodeError::Invalid(
+                format!("Unknown endpoint type: {}", t)))
+        };
+
+        let mut metadata = Vec::new();
+        let mut token = None;
+        for (tag, value) in parse_tlv(rest).map_err(DecodeError::Invalid)? {
+            let entry = match (tag, std::str::from_utf8(value)) {
+                (METADATA_ENTRY, Ok(e)) => e,
+                (METADATA_TOKEN, Ok(t)) => {
+                    token = Some(t.to_string());
+                    continue;
+                },
+                _                       => return Err(DecodeError::Invalid(
+                    format!("Invalid metadata entry: {:?}", value)))
+            };
+
+            match entry.find('=') {
+                Some(i) => metadata.push((entry[..i].to_string(), entry[i + 1..].to_string())),
+                None    => metadata.push((entry.to_string(), String::new()))
+            }
+        }
+
+        Ok(RegisterModule { module_id : bytes_to_u16(&data[..2]), endpoint, metadata, token })
+    }
+}
+
+
+const CAP_VERSION : u8 = 0;
+const CAP_COMMANDS : u8 = 1;
+const CAP_LOADER : u8 = 2;
+const CAP_FEATURE : u8 = 3;
+
+/// What an EM supports, as reported by Hello
+///
+/// Encoded as a list of `[<tag (u8)><len (u16)><value>]` entries: the protocol version (u16),
+/// the command codes it knows (one byte each), and one entry per loader and per feature
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub version : u16,
+    pub commands : Vec<u8>,
+    pub loaders : Vec<String>,
+    pub features : Vec<String>
+}
+
+impl Payload for Capabilities {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        push_tlv(&mut buf, CAP_VERSION, &self.version.to_be_bytes());
+        push_tlv(&mut buf, CAP_COMMANDS, &self.commands);
+        for loader in &self.loaders {
+            push_tlv(&mut buf, CAP_LOADER, loader.as_bytes());
+        }
+        for feature in &self.features {
+            push_tlv(&mut buf, CAP_FEATURE, feature.as_bytes());
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<Capabilities, DecodeError> {
+        let mut caps = Capabilities {
+            version : 0,
+            commands : Vec::new(),
+            loaders : Vec::new(),
+            features : Vec::new()
+        };
+
+        for (tag, value) in parse_tlv(data).map_err(DecodeError::Invalid)? {
+            match tag {
+                CAP_VERSION if value.len() == 2 => caps.version = bytes_to_u16(value),
+                CAP_VERSION                     => return Err(DecodeError::Length(value.len())),
+                CAP_COMMANDS                    => caps.commands = value.to_vec(),
+                CAP_LOADER                      => caps.loaders.push(
+                                                    String::from_utf8_lossy(value).into_owned()),
+                CAP_FEATURE                     => caps.features.push(
+                                                    String::from_utf8_lossy(value).into_owned()),
+                // ignore what newer versions add
+                _                               => ()
+            }
+        }
+
+        Ok(caps)
+    }
+}
+
+
+/// RemoveArtifacts: `[<hash (32 bytes)>]`, or empty to remove all the artifacts that are not
+/// used by a loaded module
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoveArtifacts {
+    pub hash : Option<[u8; 32]>
+}
+
+impl Payload for RemoveArtifacts {
+    fn encode(&self) -> Vec<u8> {
+        self.hash.map(|h| h.to_vec()).unwrap_or_default()
+    }
+
+    fn decode(data : &[u8]) -> Result<RemoveArtifacts, DecodeError> {
+        match data.len() {
+            0   => Ok(RemoveArtifacts { hash : None }),
+            32  => Ok(RemoveArtifacts { hash : Some(data.try_into().unwrap()) }), // should never panic
+            n   => Err(DecodeError::Length(n))
+        }
+    }
+}
+
+
+/// How the data of an upload is compressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None = 0,
+    Gzip = 1,
+    Zstd = 2
+}
+
+impl Compression {
+    pub fn from_u8(value : u8) -> Option<Compression> {
+        match value {
+            0   => Some(Compression::None),
+            1   => Some(Compression::Gzip),
+            2   => Some(Compression::Zstd),
+            _   => None
+        }
+    }
+}
+
+
+/// UploadBegin: `[<size (u32)><compression (u8)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadBegin {
+    pub size : u32,
+    pub compression : Compression
+}
+
+impl Payload for UploadBegin {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.size.to_be_bytes().to_vec();
+        buf.push(self.compression as u8);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<UploadBegin, DecodeError> {
+        if data.len() != 5 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        let compression = Compression::from_u8(data[4]).ok_or_else(||
+            DecodeError::Invalid(format!("Unknown compression: {}", data[4])))?;
+
+        Ok(UploadBegin { size : bytes_to_u32(&data[..4]), compression })
+    }
+}
+
+
+/// UploadData: `[<upload ID (u32)><offset (u32)>]`
+///
+/// Each chunk is then sent as a separate message and acknowledged with a result containing
+/// the bytes received so far. An empty chunk ends the transfer
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadData {
+    pub id : u32,
+    pub offset : u32
+}
+
+impl Payload for UploadData {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.id.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<UploadData, DecodeError> {
+        if data.len() != 8 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(UploadData { id : bytes_to_u32(&data[..4]), offset : bytes_to_u32(&data[4..]) })
+    }
+}
+
+
+/// GetModuleLogs: `[<index (u16)><tail (u16)><follow (u8)>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetModuleLogs {
+    pub index : u16,
+    /// number of past lines to send
+    pub tail : u16,
+    /// keep sending new lines
+    pub follow : bool
+}
+
+impl Payload for GetModuleLogs {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.index.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.tail.to_be_bytes());
+        buf.push(self.follow as u8);
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<GetModuleLogs, DecodeError> {
+        if data.len() != 5 {
+            return Err(DecodeError::Length(data.len()));
+        }
+
+        Ok(GetModuleLogs {
+            index : bytes_to_u16(&data[..2]),
+            tail : bytes_to_u16(&data[2..4]),
+            follow : data[4] != 0
+        })
+    }
+}
+
+
+/// Batch flag: roll back all changes if a sub-command fails
+const BATCH_ATOMIC : u8 = 1;
+
+/// Batch: `[<flags (u8)><sub-commands>]`, each sub-command being encoded as
+/// `[<code (u8)><len (u16)><payload>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub atomic : bool,
+    pub commands : Vec<(u8, Vec<u8>)>
+}
+
+impl Payload for Batch {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = match self.atomic {
+            true    => vec![BATCH_ATOMIC],
+            false   => vec![0]
+        };
+
+        for (code, payload) in &self.commands {
+            push_tlv(&mut buf, *code, payload);
+        }
+
+        buf
+    }
+
+    fn decode(data : &[u8]) -> Result<Batch, DecodeError> {
+        if data.is_empty() {
+            return Err(DecodeError::Length(0));
+        }
+
+        let commands = parse_tlv(&data[1..]).map_err(DecodeError::Invalid)?;
+
+        Ok(Batch {
+            atomic : data[0] & BATCH_ATOMIC != 0,
+            commands : commands.into_iter().map(|(code, payload)| (code, payload.to_vec())).collect()
+        })
+    }
+}
+
+
+/// Same as `reactive_net::write_message`, for any kind of stream
+pub fn write_message_to<W : Write + ?Sized>(stream : &mut W, data : &[u8]) -> Result<(), Error> {
+    if data.len() > u16::MAX as usize {
+        return Err(Error::InvalidPayload);
+    }
+
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+
+    stream.write_all(&buf).map_err(|_| Error::NetworkError)
+}
+
+
+/// Same as `reactive_net::read_message`, for any kind of stream
+pub fn read_message_from<R : Read + ?Sized>(stream : &mut R) -> Result<Vec<u8>, Error> {
+    read_frame(stream).map_err(|_| Error::NetworkError)
+}
+
+
+fn read_frame<R : Read + ?Sized>(stream : &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+
+    let mut data = vec![0u8; bytes_to_u16(&len) as usize];
+    stream.read_exact(&mut data)?;
+
+    Ok(data)
+}
+
+
+/// Same as `reactive_net::read_result`, for any kind of stream
+pub fn read_result_from<R : Read + ?Sized>(stream : &mut R) -> Result<ResultMessage, Error> {
+    let (code, payload) = read_raw_result(stream).map_err(|_| Error::NetworkError)?;
+
+    let code = match ResultCode::from_u8(code) {
+        Some(c) => c,
+        None    => return Err(Error::InvalidPayload)
+    };
+
+    match payload.is_empty() {
+        true    => Ok(ResultMessage::new(code, None)),
+        false   => Ok(ResultMessage::new(code, Some(payload)))
+    }
+}
+
+
+/// Same as `reactive_net::write_result`, for any kind of stream
+pub fn write_result_to<W : Write + ?Sized>(stream : &mut W, result : &ResultMessage)
+        -> Result<(), Error> {
+    write_raw_command(stream, result.code as u8, result.get_payload().unwrap_or(&[]))
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => Error::InvalidPayload,
+            _                           => Error::NetworkError
+        })
+}
+
+
+/// Read a result as its code and payload
+///
+/// I/O errors are returned as they are, so that e.g. timeouts can be told apart
+pub fn read_raw_result<R : Read + ?Sized>(stream : &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut code = [0u8; 1];
+    stream.read_exact(&mut code)?;
+
+    Ok((code[0], read_frame(stream)?))
+}
+
+
+/// Write a command as `[<code (u8)><len (u16)><payload>]`, also for codes that are not
+/// defined in `reactive_net`
+///
+/// A payload that does not fit in the frame is an `InvalidInput` error
+pub fn write_raw_command<W : Write + ?Sized>(stream : &mut W, code : u8, payload : &[u8])
+        -> io::Result<()> {
+    if payload.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Payload too large: {}", payload.len())));
+    }
+
+    let mut buf = Vec::with_capacity(payload.len() + 3);
+    buf.push(code);
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(payload);
+
+    stream.write_all(&buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<P : Payload + PartialEq + std::fmt::Debug>(payload : P) {
+        assert_eq!(P::decode(&payload.encode()), Ok(payload));
+    }
+
+    #[test]
+    fn add_connection() {
+        round_trip(AddConnection {
+            conn_id : 1,
+            to_sm : 2,
+            local : true,
+            em_port : 5000,
+            em_address : Ipv4Addr::new(10, 0, 0, 1)
+        });
+
+        let data = [0, 1, 0, 2, 0, 0x13, 0x88, 127, 0, 0, 1];
+        let conn = AddConnection::decode(&data).unwrap();
+        assert_eq!(conn.em_port, 5000);
+        assert_eq!(conn.em_address, Ipv4Addr::LOCALHOST);
+        assert!(!conn.local);
+    }
+
+    #[test]
+    fn call_entrypoint() {
+        round_trip(CallEntrypoint { module : 3, entry : 4, data : vec![] });
+        round_trip(CallEntrypoint { module : 3, entry : 4, data : vec![1, 2, 3] });
+
+        let call = CallEntrypoint { module : 3, entry : 4, data : vec![5] };
+        assert_eq!(call.sm_payload(), vec![0, 4, 5]);
+    }
+
+    #[test]
+    fn load_sm() {
+        round_trip(LoadSM { files : vec![] });
+        round_trip(LoadSM { files : vec![vec![1, 2, 3], vec![], vec![4]] });
+        round_trip(LoadSMResult { index : 42 });
+    }
+
+    #[test]
+    fn register_entrypoint() {
+        round_trip(RegisterEntrypoint {
+            module : 1, entry : 2, frequency : 1000, priority : Priority::Normal
+        });
+        round_trip(RegisterEntrypoint {
+            module : 1, entry : 2, frequency : 1000, priority : Priority::High
+        });
+        assert_eq!(RegisterEntrypoint::decode(&[0, 1, 0, 2, 0, 0, 3, 232, 0]).unwrap().priority,
+            Priority::Low);
+        assert!(RegisterEntrypoint::decode(&[0, 1, 0, 2, 0, 0, 3, 232, 3]).is_err());
+        round_trip(SetConnectionPriority { conn_id : 4, priority : Priority::Low });
+        round_trip(Reset);
+    }
+
+    #[test]
+    fn outputs() {
+        round_trip(ModuleOutput { entry : 2, conn_id : 7, data : vec![1] });
+        round_trip(RemoteOutput { module : 1, conn_id : 7, data : vec![1, 2, 3] });
+
+        let output = RemoteOutput { module : 1, conn_id : 7, data : vec![1, 2, 3] };
+        assert_eq!(output.sm_payload(2), vec![0, 2, 0, 7, 1, 2, 3]);
+    }
+
+    #[test]
+    fn set_connection_policy() {
+        for policy in 0..4 {
+            let policy = DeliveryPolicy::from_u8(policy).unwrap();
+            round_trip(SetConnectionPolicy { conn_id : 9, policy });
+        }
+
+        round_trip(SetConnectionOrdering { conn_id : 9, ordering : ConnectionOrdering::Fifo });
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(AddConnection::decode(&[0; 10]), Err(DecodeError::Length(10)));
+        assert_eq!(AddConnection::decode(&[0; 12]), Err(DecodeError::Length(12)));
+        assert_eq!(CallEntrypoint::decode(&[0; 3]), Err(DecodeError::Length(3)));
+        assert_eq!(LoadSMResult::decode(&[0; 3]), Err(DecodeError::Length(3)));
+        assert_eq!(Reset::decode(&[0]), Err(DecodeError::Length(1)));
+        assert_eq!(RegisterEntrypoint::decode(&[]), Err(DecodeError::Length(0)));
+        assert_eq!(ModuleOutput::decode(&[0; 4]), Err(DecodeError::Length(4)));
+        assert_eq!(RemoteOutput::decode(&[0; 6]), Err(DecodeError::Length(6)));
+        assert_eq!(SetConnectionPolicy::decode(&[0; 4]), Err(DecodeError::Length(4)));
+        assert!(SetConnectionPolicy::decode(&[0, 1, 4]).is_err());
+        assert!(SetConnectionOrdering::decode(&[0, 1, 2]).is_err());
+
+        // file sizes that do not match the data
+        assert!(LoadSM::decode(&[0, 0, 0]).is_err());
+        assert!(LoadSM::decode(&[0, 0, 0, 2, 1]).is_err());
+        assert!(LoadSM::decode(&[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn framing() {
+        let mut buf = Vec::new();
+        write_message_to(&mut buf, &[1, 2, 3]).unwrap();
+        assert_eq!(buf, vec![0, 3, 1, 2, 3]);
+        assert_eq!(read_message_from(&mut &buf[..]).unwrap(), vec![1, 2, 3]);
+
+        let mut buf = Vec::new();
+        write_raw_command(&mut buf, 75, &[0, 1]).unwrap();
+        assert_eq!(buf, vec![75, 0, 2, 0, 1]);
+
+        // results have the same layout as commands
+        assert_eq!(read_raw_result(&mut &buf[..]).unwrap(), (75, vec![0, 1]));
+
+        assert!(write_message_to(&mut Vec::new(), &vec![0; 65536]).is_err());
+        assert!(read_message_from(&mut &[0, 3, 1, 2][..]).is_err());
+        assert!(read_raw_result(&mut &[0][..]).is_err());
+        assert_eq!(write_raw_command(&mut Vec::new(), 75, &vec![0; 65536]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput);
+        assert!(read_result_from(&mut &[42, 0, 0][..]).is_err());
+
+        let mut buf = Vec::new();
+        let result = ResultMessage::new(ResultCode::BadRequest, Some(vec![1, 2]));
+        write_result_to(&mut buf, &result).unwrap();
+        assert_eq!(buf, vec![4, 0, 2, 1, 2]);
+        assert_eq!(read_result_from(&mut &buf[..]).unwrap(), result);
+    }
+
+
+    #[test]
+    fn dead_letters() {
+        let letters = DeadLetters {
+            undelivered : vec![(1, 3), (7, 1)],
+            events : vec![DeadLetter {
+                time : 1700000000,
+                conn_id : 7,
+                entry : 2,
+                code : ResultCode::UnknownConnection,
+                message : "No connection with ID 7".to_string()
+            }]
+        };
+        round_trip(letters.clone());
+        assert_eq!(letters.encode().len(), 2 * 13 + letters.events[0].encoded_len());
+
+        assert!(DeadLetters::decode(&[0, 0, 1, 7]).is_err());
+        assert!(DeadLetters::decode(&[1, 0, 13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 2, 99]).is_err());
+    }
+
+
+    #[test]
+    fn queue_stats() {
+        let stats = QueueStats {
+            queues : vec![QueueStat {
+                module : 2, depth : 3, max_depth : 8, delivered : 100, dropped : 4, rejected : 0
+            }]
+        };
+        round_trip(stats.clone());
+        assert_eq!(stats.encode().len(), 37);
+
+        assert!(QueueStats::decode(&[0, 0, 2, 0, 2]).is_err());
+        assert_eq!(QueueStats::decode(&[9, 0, 0]), Ok(QueueStats::default()));
+    }
+
+
+    #[test]
+    fn load_options() {
+        round_trip(LoadOptions::default());
+        let options = LoadOptions {
+            loader : Some("native".to_string()),
+            module_id : Some(3),
+            args : vec!["-v".to_string(), String::new()],
+            env : vec![("A".to_string(), "b=c".to_string())],
+            ready_timeout : Some(Duration::from_millis(1500)),
+            artifacts : vec![[7; 32]]
+        };
+        round_trip(options.clone());
+        round_trip(InstantiateSM { source : 2, options });
+
+        // waiting for a module needs its ID
+        assert!(LoadOptions::decode(&[4, 0, 4, 0, 0, 0, 1]).is_err());
+        assert!(LoadOptions::decode(&[1, 0, 1, 0]).is_err());
+        assert!(LoadOptions::decode(&[3, 0, 1, b'A']).is_err());
+        assert!(LoadOptions::decode(&[9, 0, 0]).is_err());
+        assert_eq!(InstantiateSM::decode(&[0]), Err(DecodeError::Length(1)));
+    }
+
+    #[test]
+    fn register_module() {
+        round_trip(RegisterModule {
+            module_id : 1,
+            endpoint : Endpoint::Tcp(6000),
+            metadata : vec![("name".to_string(), "sensor".to_string())],
+            token : Some("abc".to_string())
+        });
+        round_trip(RegisterModule {
+            module_id : 1,
+            endpoint : Endpoint::Unix(PathBuf::from("/tmp/sm1.sock")),
+            metadata : vec![],
+            token : None
+        });
+
+        // entries without a value
+        let register = RegisterModule::decode(&[0, 1, 0, 0x17, 0x70, 0, 0, 1, b'x']).unwrap();
+        assert_eq!(register.metadata, vec![("x".to_string(), String::new())]);
+
+        assert!(RegisterModule::decode(&[0, 1, 0, 0x17]).is_err());
+        assert!(RegisterModule::decode(&[0, 1, 1, 0, 5, b'/']).is_err());
+        assert!(RegisterModule::decode(&[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn capabilities() {
+        round_trip(Capabilities {
+            version : 1,
+            commands : vec![0, 1, 64],
+            loaders : vec!["sgx".to_string()],
+            features : vec!["module-logs".to_string()]
+        });
+
+        assert!(Capabilities::decode(&[0, 0, 1, 1]).is_err());
+        assert_eq!(Capabilities::decode(&[9, 0, 0]).unwrap().version, 0);
+    }
+
+    #[test]
+    fn uploads() {
+        round_trip(RemoveArtifacts { hash : None });
+        round_trip(RemoveArtifacts { hash : Some([1; 32]) });
+        round_trip(UploadBegin { size : 1 << 20, compression : Compression::Zstd });
+        round_trip(UploadData { id : 4, offset : 4096 });
+
+        assert_eq!(RemoveArtifacts::decode(&[0; 31]), Err(DecodeError::Length(31)));
+        assert!(UploadBegin::decode(&[0, 0, 0, 1, 3]).is_err());
+        assert_eq!(UploadData::decode(&[0; 4]), Err(DecodeError::Length(4)));
+    }
+
+    #[test]
+    fn module_logs() {
+        round_trip(GetModuleLogs { index : 2, tail : 100, follow : true });
+        assert_eq!(GetModuleLogs::decode(&[0; 4]), Err(DecodeError::Length(4)));
+    }
+
+    #[test]
+    fn batch() {
+        round_trip(Batch { atomic : false, commands : vec![] });
+        round_trip(Batch { atomic : true, commands : vec![(0, vec![1, 2]), (66, vec![])] });
+
+        assert_eq!(Batch::decode(&[]), Err(DecodeError::Length(0)));
+        assert!(Batch::decode(&[1, 0, 0, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        for code in 0..=17 {
+            assert_eq!(ResultCode::from_u8(code).unwrap() as u8, code);
+        }
+        assert_eq!(ResultCode::from_u8(18), None);
+
+        let result : ResultMessage = CommandError::UnknownConnection(7).into();
+        assert_eq!(result.get_code(), ResultCode::UnknownConnection);
+        assert_eq!(result.get_payload(), Some(&b"No connection with ID 7"[..]));
+
+        let e : CommandError = DecodeError::Length(3).into();
+        assert_eq!(e.code(), ResultCode::IllegalPayload);
+
+        let e : CommandError = std::io::Error::from(std::io::ErrorKind::TimedOut).into();
+        assert_eq!(e.code(), ResultCode::Timeout);
+    }
+}
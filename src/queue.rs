@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Condvar, mpsc};
+use std::thread;
+
+use log::{debug, warn};
+
+use crate::delivery::{self, DeadLetterLog};
+use crate::endpoint::{Endpoint, get_endpoint};
+use crate::output::{connect_to_sm, send_to_endpoint};
+use crate::protocol::{CommandError, ResultMessage, QueueStat, QueueStats};
+use crate::EventManager;
+
+
+/// What happens to an output for a module whose queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// the sender waits until there is room
+    Block,
+    /// the oldest output of the queue is dropped
+    DropOldest,
+    /// the new output is dropped
+    DropNewest,
+    /// the sender gets an error
+    Reject
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Overflow, String> {
+        match s {
+            "block"         => Ok(Overflow::Block),
+            "drop-oldest"   => Ok(Overflow::DropOldest),
+            "drop-newest"   => Ok(Overflow::DropNewest),
+            "reject"        => Ok(Overflow::Reject),
+            _               => Err(format!("Unknown overflow behavior: {}", s))
+        }
+    }
+}
+
+
+/// An output waiting to be delivered
+struct Item {
+    conn_id : u16,
+    entry : u16,
+    data : Vec<u8>,
+    endpoint : Endpoint,
+    /// set if the sender waits for the result of the module
+    reply : Option<mpsc::Sender<Result<ResultMessage, CommandError>>>
+}
+
+#[derive(Default)]
+struct State {
+    items : VecDeque<Item>,
+    /// whether a thread is delivering the items
+    draining : bool,
+    max_depth : usize,
+    delivered : u64,
+    dropped : u64,
+    rejected : u64
+}
+
+/// Outputs waiting to be delivered to a module, one at a time and in order
+///
+/// A thread delivers them while the queue is not empty, so that the workers of the EM do
+/// not wait for slow modules
+pub struct Queue {
+    module : u16,
+    capacity : usize,
+    overflow : Overflow,
+    state : Mutex<State>,
+    /// notified when an item is taken out
+    space : Condvar,
+    dead_letters : Arc<Mutex<DeadLetterLog>>
+}
+
+impl Queue {
+    fn new(em : &EventManager, module : u16) -> Queue {
+        Queue {
+            module,
+            capacity : em.config.queue_size,
+            overflow : em.config.queue_overflow,
+            state : Mutex::new(State::default()),
+            space : Condvar::new(),
+            dead_letters : em.dead_letters.clone()
+        }
+    }
+
+    fn push(self : &Arc<Self>, item : Item) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.items.len() >= self.capacity {
+            match self.overflow {
+                Overflow::Block         => {
+                    state = self.space.wait_while(state, |s| s.items.len() >= self.capacity)
+                        .unwrap();
+                },
+                Overflow::DropOldest    => {
+                    let oldest = state.items.pop_front().unwrap();
+                    state.dropped += 1;
+                    self.fail(oldest);
+                },
+                Overflow::DropNewest    => {
+                    state.dropped += 1;
+                    self.fail(item);
+                    return Ok(());
+                },
+                Overflow::Reject        => {
+                    state.rejected += 1;
+                    return Err(CommandError::QueueFull(self.module));
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        state.max_depth = state.max_depth.max(state.items.len());
+
+        if !state.draining {
+            state.draining = true;
+            let queue = self.clone();
+            thread::spawn(move || queue.drain());
+        }
+
+        Ok(())
+    }
+
+    /// An item was dropped: tell its sender, or record it
+    fn fail(&self, item : Item) {
+        let e = CommandError::QueueFull(self.module);
+        warn!("{}, dropping an output of connection {}", e, item.conn_id);
+
+        match item.reply {
+            Some(reply) => { let _ = reply.send(Err(e)); },
+            None        => delivery::record_to(&self.dead_letters, item.conn_id, item.entry, &e)
+        }
+    }
+
+    /// Deliver the items until the queue is empty
+    fn drain(&self) {
+        debug!("Draining the queue of module {}", self.module);
+
+        loop {
+            let item = {
+                let mut state = self.state.lock().unwrap();
+                match state.items.pop_front() {
+                    Some(item)  => item,
+                    None        => {
+                        state.draining = false;
+                        return;
+                    }
+                }
+            };
+            self.space.notify_one();
+
+            let res = send_to_endpoint(self.module, &item.endpoint, &item.data);
+            if res.is_ok() {
+                self.state.lock().unwrap().delivered += 1;
+            }
+
+            match (item.reply, res) {
+                (Some(reply), res)  => { let _ = reply.send(res); },
+                (None, Err(e))      =>
+                    delivery::record_to(&self.dead_letters, item.conn_id, item.entry, &e),
+                (None, Ok(_))       => ()
+            }
+        }
+    }
+
+    fn stat(&self, reset : bool) -> QueueStat {
+        let mut state = self.state.lock().unwrap();
+
+        let stat = QueueStat {
+            module : self.module,
+            depth : state.items.len() as u32,
+            max_depth : state.max_depth as u32,
+            delivered : state.delivered,
+            dropped : state.dropped,
+            rejected : state.rejected
+        };
+
+        if reset {
+            state.max_depth = state.items.len();
+            state.delivered = 0;
+            state.dropped = 0;
+            state.rejected = 0;
+        }
+
+        stat
+    }
+}
+
+
+/// Deliver an event of connection `conn_id` to `module`. If queues are enabled, it goes
+/// through the queue of the module, and this only waits for the result with `wait`: otherwise
+/// it returns `None` once the event is queued
+pub fn deliver(em : &EventManager, module : u16, conn_id : u16, entry : u16, data : Vec<u8>,
+        wait : bool) -> Result<Option<ResultMessage>, CommandError> {
+    if em.config.queue_size == 0 {
+        return connect_to_sm(em, module, &data).map(Some);
+    }
+
+    let endpoint = get_endpoint(em, module).ok_or(CommandError::UnknownModule(module))?;
+
+    let queue = em.queues.lock().unwrap().entry(module)
+        .or_insert_with(|| Arc::new(Queue::new(em, module)))
+        .clone();
+
+    let (reply, result) = match wait {
+        true    => {
+            let (tx, rx) = mpsc::channel();
+            (Some(tx), Some(rx))
+        },
+        false   => (None, None)
+    };
+
+    queue.push(Item { conn_id, entry, data, endpoint, reply })?;
+
+    match result {
+        Some(rx)    => rx.recv()
+            .unwrap_or_else(|_| Err(CommandError::Internal("Output was lost".to_string())))
+            .map(Some),
+        None        => Ok(None)
+    }
+}
+
+
+/// Depth and counters of the queues. If `reset` is set, the counters are reset afterwards
+pub fn stats(em : &EventManager, reset : bool) -> QueueStats {
+    let queues = em.queues.lock().unwrap();
+
+    let mut stats : Vec<QueueStat> = queues.values().map(|q| q.stat(reset)).collect();
+    stats.sort_by_key(|s| s.module);
+
+    QueueStats { queues : stats }
+}
+
+
+/// Forget the queues, outputs already queued are still delivered
+pub fn clear(em : &EventManager) {
+    em.queues.lock().unwrap().clear();
+}
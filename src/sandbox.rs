@@ -0,0 +1,809 @@
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use log::{debug, warn};
+
+
+// classic BPF opcodes used by the seccomp filter
+const BPF_LD_W_ABS : u16 = 0x20;
+const BPF_ALU_AND_K : u16 = 0x54;
+const BPF_JMP_JEQ_K : u16 = 0x15;
+const BPF_JMP_JGE_K : u16 = 0x35;
+const BPF_JMP_JSET_K : u16 = 0x45;
+const BPF_RET_K : u16 = 0x06;
+
+const SECCOMP_RET_KILL_PROCESS : u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO : u32 = 0x0005_0000;
+const SECCOMP_RET_USER_NOTIF : u32 = 0x7fc0_0000;
+const SECCOMP_RET_ALLOW : u32 = 0x7fff_0000;
+
+const SECCOMP_SET_MODE_FILTER : libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER : libc::c_long = 1 << 3;
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE : u32 = 1;
+
+// ioctls of the seccomp listener. ID_VALID is the _IOR variant, accepted by all kernels
+const SECCOMP_IOCTL_NOTIF_RECV : libc::c_ulong = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND : libc::c_ulong = 0xc018_2101;
+const SECCOMP_IOCTL_NOTIF_ID_VALID : libc::c_ulong = 0x8008_2102;
+
+// offsets in struct seccomp_data
+const SECCOMP_DATA_NR : u32 = 0;
+const SECCOMP_DATA_ARCH : u32 = 4;
+const SECCOMP_DATA_ARG0 : u32 = 16;
+const SECCOMP_DATA_ARG1 : u32 = 24;
+const SECCOMP_DATA_ARG2 : u32 = 32;
+const SECCOMP_DATA_ARG3 : u32 = 40;
+
+// syscalls of the x32 ABI have the same audit arch as x86_64
+const X32_SYSCALL_BIT : u32 = 0x4000_0000;
+const SOCK_TYPE_MASK : u32 = 0xf;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH : Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH : Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH : Option<u32> = None;
+
+// syscalls a module never needs, and that could be used to escape the sandbox
+const DENIED_SYSCALLS : &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_userfaultfd,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at
+];
+
+
+// syscalls on sockets that are made by the EM for the module, see `supervise`
+const SUPERVISED_SYSCALLS : &[libc::c_long] = &[
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen
+];
+
+
+#[repr(C)]
+struct SeccompData {
+    nr : libc::c_int,
+    arch : u32,
+    instruction_pointer : u64,
+    args : [u64; 6]
+}
+
+#[repr(C)]
+struct SeccompNotif {
+    id : u64,
+    pid : u32,
+    flags : u32,
+    data : SeccompData
+}
+
+#[repr(C)]
+struct SeccompNotifResp {
+    id : u64,
+    val : i64,
+    error : i32,
+    flags : u32
+}
+
+
+/// Restrictions applied to native modules, read from the `EM_SANDBOX*` variables
+pub struct SandboxConfig {
+    enabled : bool,
+    uid_base : u32,
+    namespaces : bool,
+    seccomp : bool,
+    max_memory : u64,
+    max_files : u64,
+    max_procs : u64
+}
+
+impl SandboxConfig {
+    /// Panics if a variable is not valid. The sandbox is only enabled with `EM_SANDBOX`, and
+    /// needs the EM to run as root
+    pub fn from_env() -> SandboxConfig {
+        let root = unsafe { libc::geteuid() } == 0;
+        let config = SandboxConfig::from_vars(&|var| env::var(var).ok(), root);
+
+        if config.enabled && config.seccomp && !supervision_supported() {
+            panic!("EM_SANDBOX_SECCOMP needs Linux 5.6 or later");
+        }
+
+        config
+    }
+
+    fn from_vars(var : &dyn Fn(&str) -> Option<String>, root : bool) -> SandboxConfig {
+        let config = SandboxConfig {
+            enabled : var_or(var, "EM_SANDBOX", false),
+            uid_base : var_or(var, "EM_SANDBOX_UID_BASE", 20000),
+            namespaces : var_or(var, "EM_SANDBOX_NAMESPACES", false),
+            seccomp : var_or(var, "EM_SANDBOX_SECCOMP", false),
+            max_memory : var_or(var, "EM_SANDBOX_MEMORY", 512 * 1024 * 1024),
+            max_files : var_or(var, "EM_SANDBOX_FILES", 256),
+            max_procs : var_or(var, "EM_SANDBOX_PROCS", 64)
+        };
+
+        if config.enabled && !root {
+            panic!("EM_SANDBOX needs the EM to run as root");
+        }
+
+        if config.seccomp && AUDIT_ARCH.is_none() {
+            panic!("EM_SANDBOX_SECCOMP is not supported on this architecture");
+        }
+
+        config
+    }
+
+    /// Modules run with the privileges of the EM
+    pub fn disabled() -> SandboxConfig {
+        SandboxConfig {
+            enabled : false,
+            uid_base : 20000,
+            namespaces : false,
+            seccomp : false,
+            max_memory : 0,
+            max_files : 0,
+            max_procs : 0
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+fn var_or<T : std::str::FromStr>(var : &dyn Fn(&str) -> Option<String>, name : &str, default : T)
+        -> T {
+    match var(name) {
+        Some(v) => v.parse::<T>().unwrap_or_else(|_| panic!("Invalid value for {}", name)),
+        None    => default
+    }
+}
+
+
+/// Create the private working directory of module `ind`, owned by its dedicated UID
+pub fn create_module_dir(config : &SandboxConfig, base : &Path, ind : u16)
+        -> io::Result<PathBuf> {
+    // the unprivileged UID must be able to traverse the EM's directory, but not list it
+    fs::set_permissions(base, fs::Permissions::from_mode(0o711))?;
+
+    let dir = base.join(format!("sm{}.d", ind));
+    fs::create_dir(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+
+    let uid = config.uid_base + ind as u32;
+    chown(&dir, uid, uid)?;
+
+    Ok(dir)
+}
+
+
+/// Make an uploaded module executable (but not writable) by everyone
+pub fn make_executable(filename : &Path) -> io::Result<()> {
+    fs::set_permissions(filename, fs::Permissions::from_mode(0o555))
+}
+
+
+/// Build the command that runs module `ind` inside the sandbox
+///
+/// The module runs in `dir` with a cleared environment, under its own UID/GID and with
+/// resource limits. Optionally, it gets its own mount/IPC/UTS namespaces and a seccomp
+/// filter, that denies the syscalls that could be used to escape the sandbox and only allows
+/// Unix sockets and IPv4 stream sockets. The EM makes the `connect`, `bind` and `listen`
+/// calls of the module on IPv4 sockets (see `supervise`): it can only listen on the loopback
+/// interface and connect to `em_port`. If this cannot be set up, the module does not start.
+pub fn sandboxed_command(config : &SandboxConfig, filename : &Path, dir : &Path, ind : u16,
+        em_port : u16) -> Command {
+    let mut command = Command::new(filename);
+    command.current_dir(dir).env_clear().env("HOME", dir);
+
+    let uid = config.uid_base + ind as u32;
+    let namespaces = config.namespaces;
+    let limits = [
+        (libc::RLIMIT_AS, config.max_memory),
+        (libc::RLIMIT_NOFILE, config.max_files),
+        (libc::RLIMIT_NPROC, config.max_procs),
+        (libc::RLIMIT_CORE, 0)
+    ];
+
+    // everything is allocated here: only async-signal-safe calls are allowed after fork.
+    // The supervisor gets the seccomp listener of the module through `socket`
+    let seccomp = match config.seccomp {
+        true    => Some((seccomp_filter(), start_supervisor(em_port)
+                    .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)))),
+        false   => None
+    };
+
+    debug!("Sandbox for module {}: UID {}, namespaces: {}, seccomp: {}",
+        ind, uid, namespaces, config.seccomp);
+
+    unsafe {
+        command.pre_exec(move || {
+            // namespaces need privileges: enter them before dropping the UID
+            if namespaces &&
+                    libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for (resource, value) in limits.iter() {
+                let limit = libc::rlimit { rlim_cur : *value, rlim_max : *value };
+                if libc::setrlimit(*resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if libc::setgroups(0, std::ptr::null()) != 0 ||
+                    libc::setgid(uid) != 0 || libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if let Some((filter, socket)) = &seccomp {
+                let socket = match socket {
+                    Ok(s)   => s,
+                    Err(e)  => return Err(io::Error::from_raw_os_error(*e))
+                };
+                let prog = libc::sock_fprog {
+                    len : filter.len() as libc::c_ushort,
+                    filter : filter.as_ptr() as *mut libc::sock_filter
+                };
+
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let listener = libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER,
+                    SECCOMP_FILTER_FLAG_NEW_LISTENER, &prog as *const libc::sock_fprog);
+                if listener < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let sent = send_fd(socket.as_raw_fd(), listener as RawFd);
+                libc::close(listener as RawFd);
+                sent?;
+            }
+
+            Ok(())
+        });
+    }
+
+    command
+}
+
+
+fn chown(path : &Path, uid : u32, gid : u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+
+    match unsafe { libc::chown(path.as_ptr(), uid, gid) } {
+        0   => Ok(()),
+        _   => Err(io::Error::last_os_error())
+    }
+}
+
+
+/// Whether the kernel lets the EM make the syscalls of the modules (see `supervise`)
+fn supervision_supported() -> bool {
+    unsafe {
+        let pidfd = libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0);
+        if pidfd < 0 {
+            return false;
+        }
+
+        let fd = libc::syscall(libc::SYS_pidfd_getfd, pidfd, pidfd, 0);
+        if fd >= 0 {
+            libc::close(fd as RawFd);
+        }
+        libc::close(pidfd as RawFd);
+
+        fd >= 0
+    }
+}
+
+
+/// Start the thread that supervises a module, returns the socket the module sends its
+/// seccomp listener to. The thread stops if the module is not started
+fn start_supervisor(em_port : u16) -> io::Result<UnixStream> {
+    let (socket, module) = UnixStream::pair()?;
+
+    thread::spawn(move || {
+        let listener = match receive_fd(&socket) {
+            Ok(fd)  => unsafe { File::from_raw_fd(fd) },
+            Err(_)  => return
+        };
+        drop(socket);
+
+        supervise(&listener, em_port);
+    });
+
+    Ok(module)
+}
+
+
+/// Handle the calls notified by the seccomp filter of a module, until it exits
+///
+/// The EM makes the `connect`, `bind` and `listen` calls of the module on IPv4 sockets
+/// itself, on a copy of the socket and of the address, so that the module cannot change
+/// them after they were checked. It can only listen on the loopback interface and connect
+/// to the EM. Unix sockets are left to the kernel, that checks them with the credentials of
+/// the module
+fn supervise(listener : &File, em_port : u16) {
+    let fd = listener.as_raw_fd();
+
+    loop {
+        let mut poll = libc::pollfd { fd, events : libc::POLLIN, revents : 0 };
+        if unsafe { libc::poll(&mut poll, 1, -1) } < 0 {
+            match io::Error::last_os_error().kind() {
+                io::ErrorKind::Interrupted  => continue,
+                _                           => return
+            }
+        }
+
+        // the module and its children exited
+        if poll.revents & libc::POLLHUP != 0 {
+            return;
+        }
+
+        let mut req : SeccompNotif = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(fd, SECCOMP_IOCTL_NOTIF_RECV as _, &mut req) } != 0 {
+            match io::Error::last_os_error().raw_os_error() {
+                // the caller was killed before we got the call
+                Some(libc::ENOENT) | Some(libc::EINTR)  => continue,
+                _                                       => return
+            }
+        }
+
+        let (error, flags) = match supervised_call(listener, &req, em_port) {
+            Ok(true)    => (0, SECCOMP_USER_NOTIF_FLAG_CONTINUE),
+            Ok(false)   => (0, 0),
+            Err(e)      => (-e.raw_os_error().unwrap_or(libc::EPERM), 0)
+        };
+
+        // this fails if the caller was interrupted in the meantime
+        let resp = SeccompNotifResp { id : req.id, val : 0, error, flags };
+        unsafe { libc::ioctl(fd, SECCOMP_IOCTL_NOTIF_SEND as _, &resp) };
+    }
+}
+
+/// Make the call of `req`, returns whether the kernel should make it instead
+fn supervised_call(listener : &File, req : &SeccompNotif, em_port : u16) -> io::Result<bool> {
+    let nr = req.data.nr as libc::c_long;
+    let socket = get_fd(req.pid, req.data.args[0] as RawFd)?;
+
+    if socket_domain(&socket)? != libc::AF_INET {
+        return Ok(true);
+    }
+
+    let addr = match nr {
+        libc::SYS_listen    => local_address(&socket)?,
+        _                   => read_address(req.pid, req.data.args[1], req.data.args[2])?
+    };
+
+    // the caller could have exited, and its PID been reused, while we were reading
+    if unsafe { libc::ioctl(listener.as_raw_fd(), SECCOMP_IOCTL_NOTIF_ID_VALID as _, &req.id) }
+            != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if !is_allowed(nr, &addr, em_port) {
+        warn!("Denied a network call ({}) of a sandboxed module to {}", nr, addr);
+        return Err(io::Error::from_raw_os_error(libc::EACCES));
+    }
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family : libc::AF_INET as libc::sa_family_t,
+        sin_port : addr.port().to_be(),
+        sin_addr : libc::in_addr { s_addr : u32::from(*addr.ip()).to_be() },
+        sin_zero : [0; 8]
+    };
+    let sockaddr_ptr = &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr;
+    let len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    let res = unsafe {
+        match nr {
+            libc::SYS_connect   => libc::connect(socket.as_raw_fd(), sockaddr_ptr, len),
+            libc::SYS_bind      => libc::bind(socket.as_raw_fd(), sockaddr_ptr, len),
+            _                   => libc::listen(socket.as_raw_fd(),
+                                    req.data.args[1] as libc::c_int)
+        }
+    };
+
+    match res {
+        0   => Ok(false),
+        _   => Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether a sandboxed module can make call `nr` with `addr`: it can only bind and listen
+/// on the loopback interface, on an unprivileged port, and connect to the EM
+fn is_allowed(nr : libc::c_long, addr : &SocketAddrV4, em_port : u16) -> bool {
+    let port = addr.port();
+
+    addr.ip().is_loopback() && match nr {
+        libc::SYS_connect   => port == em_port,
+        libc::SYS_bind      => port == 0 || port >= 1024,
+        libc::SYS_listen    => true,
+        _                   => false
+    }
+}
+
+/// Copy of file descriptor `fd` of thread `pid`
+fn get_fd(pid : u32, fd : RawFd) -> io::Result<File> {
+    // pidfd_open() needs the PID of the process, not of the thread
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let tgid = status.lines()
+        .find_map(|l| l.strip_prefix("Tgid:"))
+        .and_then(|t| t.trim().parse::<libc::pid_t>().ok())
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ESRCH))?;
+
+    unsafe {
+        let pidfd = libc::syscall(libc::SYS_pidfd_open, tgid, 0);
+        if pidfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pidfd = File::from_raw_fd(pidfd as RawFd);
+
+        match libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) {
+            -1  => Err(io::Error::last_os_error()),
+            fd  => Ok(File::from_raw_fd(fd as RawFd))
+        }
+    }
+}
+
+fn socket_domain(socket : &File) -> io::Result<libc::c_int> {
+    let mut domain : libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    match unsafe { libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_DOMAIN,
+            &mut domain as *mut libc::c_int as *mut libc::c_void, &mut len) } {
+        0   => Ok(domain),
+        _   => Err(io::Error::last_os_error())
+    }
+}
+
+/// Address an IPv4 socket is bound to, 0.0.0.0 if it is not bound
+fn local_address(socket : &File) -> io::Result<SocketAddrV4> {
+    let mut sockaddr : libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    match unsafe { libc::getsockname(socket.as_raw_fd(),
+            &mut sockaddr as *mut libc::sockaddr_in as *mut libc::sockaddr, &mut len) } {
+        0   => Ok(SocketAddrV4::new(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)),
+                u16::from_be(sockaddr.sin_port))),
+        _   => Err(io::Error::last_os_error())
+    }
+}
+
+/// IPv4 address of `len` bytes at `ptr` in the memory of thread `pid`
+fn read_address(pid : u32, ptr : u64, len : u64) -> io::Result<SocketAddrV4> {
+    let mut buf = [0u8; 16];
+    if (len as usize) < buf.len() {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    File::open(format!("/proc/{}/mem", pid))?.read_exact_at(&mut buf, ptr)?;
+
+    // struct sockaddr_in: family in host order, then port and address in network order
+    match u16::from_ne_bytes([buf[0], buf[1]]) as libc::c_int {
+        libc::AF_INET   => Ok(SocketAddrV4::new(Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]),
+                            u16::from_be_bytes([buf[2], buf[3]]))),
+        _               => Err(io::Error::from_raw_os_error(libc::EAFNOSUPPORT))
+    }
+}
+
+
+/// Send `fd` over the Unix socket `socket`. Called after fork
+unsafe fn send_fd(socket : RawFd, fd : RawFd) -> io::Result<()> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec { iov_base : data.as_mut_ptr() as *mut libc::c_void, iov_len : 1 };
+    let mut control = [0u64; 4];
+
+    let mut msg : libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as _;
+
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+    match libc::sendmsg(socket, &msg, 0) {
+        1   => Ok(()),
+        _   => Err(io::Error::last_os_error())
+    }
+}
+
+/// Receive a file descriptor sent with `send_fd`
+fn receive_fd(socket : &UnixStream) -> io::Result<RawFd> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec { iov_base : data.as_mut_ptr() as *mut libc::c_void, iov_len : 1 };
+    let mut control = [0u64; 4];
+
+    unsafe {
+        let mut msg : libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        if libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) != 1 {
+            return Err(io::Error::from_raw_os_error(libc::ECONNRESET));
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        match cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            true    => Err(io::Error::from_raw_os_error(libc::EBADMSG)),
+            false   => Ok(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+        }
+    }
+}
+
+
+fn stmt(code : u16, k : u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt : 0, jf : 0, k }
+}
+
+fn jump(code : u16, k : u32, jt : u8, jf : u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+fn deny(syscall : libc::c_long, errno : libc::c_int) -> [libc::sock_filter; 2] {
+    [
+        jump(BPF_JMP_JEQ_K, syscall as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | errno as u32)
+    ]
+}
+
+fn notify(syscall : libc::c_long) -> [libc::sock_filter; 2] {
+    [
+        jump(BPF_JMP_JEQ_K, syscall as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_USER_NOTIF)
+    ]
+}
+
+/// Deny `syscall` if the argument at `offset` has flag `MSG_FASTOPEN`, that connects
+/// without `connect`
+fn deny_fast_open(syscall : libc::c_long, offset : u32) -> [libc::sock_filter; 5] {
+    [
+        jump(BPF_JMP_JEQ_K, syscall as u32, 0, 4),
+        stmt(BPF_LD_W_ABS, offset),
+        jump(BPF_JMP_JSET_K, libc::MSG_FASTOPEN as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR)
+    ]
+}
+
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let mut filter = vec![
+        // kill the module if it uses another syscall ABI
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH.unwrap(), 1, 0), // checked in SandboxConfig::from_env
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS)
+    ];
+
+    for syscall in DENIED_SYSCALLS {
+        filter.extend_from_slice(&deny(*syscall, libc::EPERM));
+    }
+
+    // the flags of clone3() cannot be inspected: make the libc fall back to clone()
+    filter.extend_from_slice(&deny(libc::SYS_clone3, libc::ENOSYS));
+
+    for syscall in SUPERVISED_SYSCALLS {
+        filter.extend_from_slice(&notify(*syscall));
+    }
+
+    filter.extend_from_slice(&deny_fast_open(libc::SYS_sendto, SECCOMP_DATA_ARG3));
+    filter.extend_from_slice(&deny_fast_open(libc::SYS_sendmsg, SECCOMP_DATA_ARG2));
+    filter.extend_from_slice(&deny_fast_open(libc::SYS_sendmmsg, SECCOMP_DATA_ARG3));
+
+    filter.extend_from_slice(&[
+        // clone(): no new user namespace
+        jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 3),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0),
+        jump(BPF_JMP_JSET_K, libc::CLONE_NEWUSER as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+
+        // socket(): only Unix sockets, and IPv4 stream sockets to reach the EM (see
+        // `supervise`)
+        jump(BPF_JMP_JEQ_K, libc::SYS_socket as u32, 0, 7),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0),
+        jump(BPF_JMP_JEQ_K, libc::AF_UNIX as u32, 5, 0),
+        jump(BPF_JMP_JEQ_K, libc::AF_INET as u32, 0, 3),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG1),
+        stmt(BPF_ALU_AND_K, SOCK_TYPE_MASK),
+        jump(BPF_JMP_JEQ_K, libc::SOCK_STREAM as u32, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EAFNOSUPPORT as u32),
+
+        stmt(BPF_RET_K, SECCOMP_RET_ALLOW)
+    ]);
+
+    filter
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Run the filter on a syscall, as the kernel would
+    fn run(filter : &[libc::sock_filter], arch : u32, nr : libc::c_long, args : [u32; 4]) -> u32 {
+        let load = |k : u32| match k {
+            SECCOMP_DATA_NR     => nr as u32,
+            SECCOMP_DATA_ARCH   => arch,
+            SECCOMP_DATA_ARG0   => args[0],
+            SECCOMP_DATA_ARG1   => args[1],
+            SECCOMP_DATA_ARG2   => args[2],
+            SECCOMP_DATA_ARG3   => args[3],
+            _                   => panic!("Unexpected offset {}", k)
+        };
+
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let ins = &filter[pc];
+            pc += 1;
+
+            let taken = match ins.code {
+                BPF_LD_W_ABS    => { acc = load(ins.k); continue },
+                BPF_ALU_AND_K   => { acc &= ins.k; continue },
+                BPF_RET_K       => return ins.k,
+                BPF_JMP_JEQ_K   => acc == ins.k,
+                BPF_JMP_JGE_K   => acc >= ins.k,
+                BPF_JMP_JSET_K  => acc & ins.k != 0,
+                code            => panic!("Unexpected opcode {:#x}", code)
+            };
+
+            pc += match taken {
+                true    => ins.jt,
+                false   => ins.jf
+            } as usize;
+        }
+    }
+
+    fn syscall(nr : libc::c_long, args : [u32; 2]) -> u32 {
+        run(&seccomp_filter(), AUDIT_ARCH.unwrap(), nr, [args[0], args[1], 0, 0])
+    }
+
+    fn errno(errno : libc::c_int) -> u32 {
+        SECCOMP_RET_ERRNO | errno as u32
+    }
+
+    #[test]
+    fn seccomp_abi() {
+        assert_eq!(run(&seccomp_filter(), 0x4000_0003, libc::SYS_read, [0; 4]),
+            SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(syscall(libc::SYS_read | X32_SYSCALL_BIT as libc::c_long, [0, 0]),
+            SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(syscall(libc::SYS_read, [0, 0]), SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn seccomp_denied() {
+        for nr in DENIED_SYSCALLS {
+            assert_eq!(syscall(*nr, [0, 0]), errno(libc::EPERM));
+        }
+
+        assert_eq!(syscall(libc::SYS_clone3, [0, 0]), errno(libc::ENOSYS));
+        assert_eq!(syscall(libc::SYS_clone, [libc::CLONE_NEWUSER as u32, 0]),
+            errno(libc::EPERM));
+        assert_eq!(syscall(libc::SYS_clone, [libc::SIGCHLD as u32, 0]), SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn seccomp_sockets() {
+        let socket = |family : libc::c_int, kind : libc::c_int|
+            syscall(libc::SYS_socket, [family as u32, kind as u32]);
+
+        assert_eq!(socket(libc::AF_UNIX, libc::SOCK_DGRAM), SECCOMP_RET_ALLOW);
+        assert_eq!(socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC),
+            SECCOMP_RET_ALLOW);
+        assert_eq!(socket(libc::AF_INET, libc::SOCK_DGRAM), errno(libc::EAFNOSUPPORT));
+        assert_eq!(socket(libc::AF_INET6, libc::SOCK_STREAM), errno(libc::EAFNOSUPPORT));
+        assert_eq!(socket(libc::AF_NETLINK, libc::SOCK_RAW), errno(libc::EAFNOSUPPORT));
+    }
+
+    #[test]
+    fn seccomp_network() {
+        for nr in SUPERVISED_SYSCALLS {
+            assert_eq!(syscall(*nr, [3, 0]), SECCOMP_RET_USER_NOTIF);
+        }
+
+        let fast_open = libc::MSG_FASTOPEN as u32;
+        let filter = seccomp_filter();
+        let send = |nr, args| run(&filter, AUDIT_ARCH.unwrap(), nr, args);
+
+        assert_eq!(send(libc::SYS_sendto, [3, 0, 0, fast_open]), errno(libc::EPERM));
+        assert_eq!(send(libc::SYS_sendto, [3, 0, 0, libc::MSG_NOSIGNAL as u32]),
+            SECCOMP_RET_ALLOW);
+        assert_eq!(send(libc::SYS_sendmsg, [3, 0, fast_open, 0]), errno(libc::EPERM));
+        assert_eq!(send(libc::SYS_sendmsg, [3, 0, 0, fast_open]), SECCOMP_RET_ALLOW);
+        assert_eq!(send(libc::SYS_sendmmsg, [3, 0, 1, fast_open]), errno(libc::EPERM));
+    }
+
+    #[test]
+    fn supervised_addresses() {
+        let addr = |ip : [u8; 4], port| SocketAddrV4::new(Ipv4Addr::from(ip), port);
+
+        assert!(is_allowed(libc::SYS_connect, &addr([127, 0, 0, 1], 5000), 5000));
+        assert!(!is_allowed(libc::SYS_connect, &addr([127, 0, 0, 1], 22), 5000));
+        assert!(!is_allowed(libc::SYS_connect, &addr([10, 0, 0, 1], 5000), 5000));
+
+        assert!(is_allowed(libc::SYS_bind, &addr([127, 0, 0, 1], 0), 5000));
+        assert!(is_allowed(libc::SYS_bind, &addr([127, 0, 0, 2], 5001), 5000));
+        assert!(!is_allowed(libc::SYS_bind, &addr([127, 0, 0, 1], 80), 5000));
+        assert!(!is_allowed(libc::SYS_bind, &addr([0, 0, 0, 0], 5001), 5000));
+
+        assert!(is_allowed(libc::SYS_listen, &addr([127, 0, 0, 1], 5001), 5000));
+        assert!(!is_allowed(libc::SYS_listen, &addr([0, 0, 0, 0], 40000), 5000));
+    }
+
+    fn config(vars : &[(&str, &str)], root : bool) -> SandboxConfig {
+        let vars : HashMap<String, String> = vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        SandboxConfig::from_vars(&|var| vars.get(var).cloned(), root)
+    }
+
+    #[test]
+    fn config_defaults() {
+        assert!(!config(&[], true).is_enabled());
+        assert!(!config(&[], false).is_enabled());
+        assert!(config(&[("EM_SANDBOX", "true")], true).is_enabled());
+
+        let config = config(&[("EM_SANDBOX", "true"), ("EM_SANDBOX_UID_BASE", "30000"),
+            ("EM_SANDBOX_FILES", "8")], true);
+        assert_eq!((config.uid_base, config.max_files, config.max_procs), (30000, 8, 64));
+    }
+
+    #[test]
+    #[should_panic(expected = "EM_SANDBOX needs the EM to run as root")]
+    fn config_not_root() {
+        config(&[("EM_SANDBOX", "true")], false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for EM_SANDBOX_MEMORY")]
+    fn config_invalid() {
+        config(&[("EM_SANDBOX_MEMORY", "lots")], true);
+    }
+}
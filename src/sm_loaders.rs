@@ -1,117 +1,497 @@
-use std::net::TcpStream;
-use std::io::prelude::*;
-use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio, Child};
+use std::collections::HashMap;
+use std::fs;
 
 use crate::helpers::*;
-use crate::MODULES;
-use reactive_net::{ResultCode, ResultMessage};
+use crate::protocol::{Payload, LoadSMResult, ResultCode, ResultMessage, CommandError, Stream};
+use crate::enclave::check_enclave;
+use crate::sandbox::*;
+use crate::modules::{Module, new_token};
+use crate::store;
+use crate::endpoint::{get_endpoint, is_listening, is_registered, socket_path};
+use crate::EventManager;
 
-use log::{debug, error};
+use log::{debug, warn};
 
-pub fn load_sm_sgx(stream: &mut TcpStream) -> Option<ResultMessage> {
-    let ind = get_sm_index();
+pub use crate::protocol::LoadOptions;
 
-    let dir_path =  &*crate::TEMP_DIR.path();
-    let sgxs = dir_path.join(&format!("m{}.sgxs", ind));
-    let sgxs = sgxs.to_str().unwrap(); // should never panic
-    let sig = dir_path.join(&format!("m{}.sig", ind));
-    let sig = sig.to_str().unwrap(); // should never panic
 
-    // payload is: [<sgxs_size><sgxs><sig_size><sig>]
+const READY_POLL_INTERVAL : Duration = Duration::from_millis(50);
+const READY_DIAGNOSTIC_LINES : usize = 20;
 
-    // read data and store files on disk
-    let mut buf : [u8; 4] = [0; 4];
 
-    //read sgxs file
-    if let Err(_) = stream.read_exact(&mut buf) {
-        error!("Wrong payload for handle_load_sm");
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+/// Error returned by a loader while preparing a module
+pub enum LoadError {
+    /// the module must not be launched (e.g. it does not pass a policy check)
+    Rejected(String),
+    /// the EM failed to set up the module
+    Internal(String)
+}
+
+
+/// A module that has been uploaded to the EM
+#[derive(Clone)]
+pub struct ModuleFiles {
+    pub index : u16,
+    pub dir : PathBuf,
+    pub files : Vec<PathBuf>
+}
+
+impl ModuleFiles {
+    /// Path of the file at position `i` in `ModuleLoader::files`
+    pub fn file(&self, i : usize) -> &str {
+        self.files[i].to_str().unwrap() // should never panic
     }
+}
+
+
+/// A kind of module the EM can run
+///
+/// A loader defines which files are uploaded with LoadSM, where they are stored and how
+/// the module is launched. Loaders are registered in `default_loaders`.
+pub trait ModuleLoader : Send + Sync {
+    /// Name used in LoadModule to select this loader
+    fn name(&self) -> &'static str;
 
-    let sgxs_size = bytes_to_u32(&buf);
-    if let Err(msg) = write_to_file(stream, sgxs_size, &sgxs) {
-        error!("{}", msg);
-        return Some(ResultMessage::new(ResultCode::InternalError, None));
+    /// Files uploaded with LoadSM, in the order they are sent. Each file is sent as
+    /// `[<size (u32)><data>]`
+    fn files(&self) -> &'static [&'static str];
+
+    /// Directory where the files of module `ind` are stored
+    fn module_dir(&self, em : &EventManager, _ind : u16) -> std::io::Result<PathBuf> {
+        Ok(em.temp_dir().to_path_buf())
     }
 
-    // read signature
-    if let Err(_) = stream.read_exact(&mut buf) {
-        error!("Wrong payload for handle_load_sm");
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+    /// Path of one of the files returned by `files`
+    fn file_path(&self, dir : &Path, ind : u16, file : &str) -> PathBuf;
+
+    /// Called when all files are on disk, before launching the module
+    fn prepare(&self, _em : &EventManager, _module : &ModuleFiles) -> Result<(), LoadError> {
+        Ok(())
     }
 
-    let sig_size = bytes_to_u32(&buf);
-    if let Err(msg) = write_to_file(stream, sig_size, &sig) {
-        error!("{}", msg);
-        return Some(ResultMessage::new(ResultCode::InternalError, None));
+    /// Command that launches the module. Extra arguments and environment variables from
+    /// `LoadOptions` are added by the caller
+    fn command(&self, em : &EventManager, module : &ModuleFiles, options : &LoadOptions)
+        -> Command;
+}
+
+
+pub struct SgxLoader;
+
+impl ModuleLoader for SgxLoader {
+    fn name(&self) -> &'static str {
+        "sgx"
     }
 
+    fn files(&self) -> &'static [&'static str] {
+        &["sgxs", "sig"]
+    }
 
-    // run enclave
-    match Command::new("ftxsgx-runner").args(&["-s", "coresident", &sgxs]).spawn() {
-        Ok(module)  => {
-            let mut modules = MODULES.lock().unwrap();
-            modules.push(module);
-            debug!("Module started successfully");
-            Some(ResultMessage::new(ResultCode::Ok, None))
+    fn file_path(&self, dir : &Path, ind : u16, file : &str) -> PathBuf {
+        dir.join(format!("m{}.{}", ind, file))
+    }
+
+    fn prepare(&self, em : &EventManager, module : &ModuleFiles) -> Result<(), LoadError> {
+        // check that the enclave matches its signature and is allowed to run here
+        check_enclave(module.file(0), module.file(1), em.config.sgx_policy.as_ref())
+            .map_err(LoadError::Rejected)
+    }
+
+    fn command(&self, em : &EventManager, module : &ModuleFiles, options : &LoadOptions)
+            -> Command {
+        // the runner is never empty (checked when reading the configuration)
+        let runner : Vec<String> = em.config.sgx_runner.iter()
+            .map(|arg| expand_placeholders(arg, module, options))
+            .collect();
+
+        let mut command = Command::new(&runner[0]);
+        command.args(&runner[1..]);
+        command
+    }
+}
+
+
+pub struct NativeLoader;
+
+impl ModuleLoader for NativeLoader {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn files(&self) -> &'static [&'static str] {
+        &["exe"]
+    }
+
+    fn module_dir(&self, em : &EventManager, ind : u16) -> std::io::Result<PathBuf> {
+        // sandboxed modules get a private directory, owned by their dedicated UID
+        match em.config.sandbox.is_enabled() {
+            true    => create_module_dir(&em.config.sandbox, em.temp_dir(), ind),
+            false   => Ok(em.temp_dir().to_path_buf())
         }
-        Err(_)      => {
-            error!("program failed to start");
-            Some(ResultMessage::new(ResultCode::InternalError, None))
+    }
+
+    fn file_path(&self, dir : &Path, ind : u16, _file : &str) -> PathBuf {
+        dir.join(format!("sm{}", ind))
+    }
+
+    fn prepare(&self, _em : &EventManager, module : &ModuleFiles) -> Result<(), LoadError> {
+        make_executable(&module.files[0])
+            .map_err(|e| LoadError::Internal(format!("Failed to set permissions: {}", e)))
+    }
+
+    fn command(&self, em : &EventManager, module : &ModuleFiles, _options : &LoadOptions)
+            -> Command {
+        match em.config.sandbox.is_enabled() {
+            true    => sandboxed_command(&em.config.sandbox, &module.files[0], &module.dir,
+                        module.index, em.port()),
+            false   => Command::new(&module.files[0])
         }
     }
 }
 
-pub fn load_sm_native(stream: &mut TcpStream) -> Option<ResultMessage> {
-    let ind = get_sm_index();
 
-    let dir_path =  &*crate::TEMP_DIR.path();
-    let filename = dir_path.join(&format!("sm{}", ind));
-    let filename = filename.to_str().unwrap();    // payload is: [<exe_size><exe>]
+pub fn default_loaders() -> HashMap<&'static str, Box<dyn ModuleLoader>> {
+    let loaders : Vec<Box<dyn ModuleLoader>> = vec![
+        Box::new(SgxLoader),
+        Box::new(NativeLoader)
+    ];
+
+    loaders.into_iter().map(|l| (l.name(), l)).collect()
+}
+
+
+/// Replace the placeholders in an argument of a runner command line
+///
+/// Supported placeholders are `{sgxs}`, `{sig}`, `{dir}`, `{socket}` (see
+/// `endpoint::socket_path`), `{index}` and `{id}` (the module ID given in LoadModule, or the
+/// index if missing)
+pub fn expand_placeholders(arg : &str, module : &ModuleFiles, options : &LoadOptions) -> String {
+    let id = options.module_id.unwrap_or(module.index);
+    let file = |i : usize| module.files.get(i).map(|f| f.to_str().unwrap()).unwrap_or("");
+
+    arg.replace("{sgxs}", file(0))
+        .replace("{sig}", file(1))
+        .replace("{dir}", module.dir.to_str().unwrap())
+        .replace("{socket}", socket_path(&module.dir, module.index).to_str().unwrap())
+        .replace("{index}", &module.index.to_string())
+        .replace("{id}", &id.to_string())
+}
+
+
+pub fn get_loader<'a>(em : &'a EventManager, name : &str) -> Option<&'a dyn ModuleLoader> {
+    em.loaders.get(name).map(|l| l.as_ref())
+}
+
+
+/// Where the files of a new module come from
+enum Source<'a> {
+    /// sent after the command
+    Stream(&'a mut dyn Stream),
+    /// in the store, see `LoadOptions::artifacts`
+    Store,
+    /// the files of another module
+    Module(ModuleFiles)
+}
+
+
+/// Upload and launch a module
+///
+/// On success, the result contains the index of the module (u16), used to refer to it in
+/// later commands (e.g. GetModuleLogs). If `options` references artifacts, the files are
+/// taken from the store and nothing is read from `stream`.
+pub fn load_module(em : &EventManager, loader : &dyn ModuleLoader, stream : &mut dyn Stream,
+        options : LoadOptions) -> Result<ResultMessage, CommandError> {
+    // payload is: [<size><data>] for each file of the loader, unless the files are
+    // referenced by hash in the options
+    match options.artifacts.len() {
+        0                               => load(em, loader, Source::Stream(stream), options),
+        n if n == loader.files().len()  => load(em, loader, Source::Store, options),
+        n                               => Err(CommandError::Malformed(format!(
+            "Expected {} artifacts, got {}", loader.files().len(), n)))
+    }
+}
+
+
+fn load(em : &EventManager, loader : &dyn ModuleLoader, source : Source,
+        mut options : LoadOptions) -> Result<ResultMessage, CommandError> {
+    let ind = em.get_sm_index();
+    debug!("Loading module {} with loader {}", ind, loader.name());
+
+    let dir = loader.module_dir(em, ind).map_err(|e|
+        CommandError::Internal(format!("Failed to create module directory: {}", e)))?;
+
+    let mut module = ModuleFiles {
+        index : ind,
+        dir,
+        files : Vec::with_capacity(loader.files().len())
+    };
+
+    let res = read_files(em, loader, source, &mut module, &options)
+        .and_then(|_| match loader.prepare(em, &module) {
+            Ok(_)                           => Ok(()),
+            Err(LoadError::Rejected(e))     => Err(CommandError::Rejected(e)),
+            Err(LoadError::Internal(e))     => Err(CommandError::Internal(e))
+        });
+
+    if let Err(e) = res {
+        remove_files(em, &module);
+        return Err(e);
+    }
 
-    // read data and store files on disk
+    options.loader = Some(loader.name().to_string());
+    start_module(em, loader, module, options)
+}
+
+
+/// Write the files of a module to its directory
+///
+/// Only artifacts that were uploaded on their own are in the store, files sent with the
+/// command are not kept after the module is unloaded.
+fn read_files(em : &EventManager, loader : &dyn ModuleLoader, mut source : Source,
+        module : &mut ModuleFiles, options : &LoadOptions) -> Result<(), CommandError> {
     let mut buf : [u8; 4] = [0; 4];
 
-    //read exec file
-    if let Err(_) = stream.read_exact(&mut buf) {
-        error!("Wrong payload for handle_load_sm");
-        return Some(ResultMessage::new(ResultCode::IllegalPayload, None));
+    for (i, file) in loader.files().iter().enumerate() {
+        let filename = loader.file_path(&module.dir, module.index, file);
+        module.files.push(filename.clone());
+
+        let stream = match &mut source {
+            Source::Stream(stream)  => stream,
+            Source::Store           => {
+                store::copy_to(em, &options.artifacts[i], &filename)?;
+                continue;
+            },
+            Source::Module(from)    => {
+                fs::copy(&from.files[i], &filename)?;
+                continue;
+            }
+        };
+
+        if let Err(e) = stream.read_exact(&mut buf) {
+            return Err(CommandError::Malformed(format!("Failed to read the size of {}: {}",
+                file, e)));
+        }
+
+        let size = bytes_to_u32(&buf);
+        write_to_file(*stream, size, filename.to_str().unwrap())?;
     }
 
-    let exec_size = bytes_to_u32(&buf);
-    if let Err(msg) = write_to_file(stream, exec_size, &filename) {
-        error!("{}", msg);
-        return Some(ResultMessage::new(ResultCode::InternalError, None));
+    Ok(())
+}
+
+
+/// Remove the files and the private directory of a module
+fn remove_files(em : &EventManager, module : &ModuleFiles) {
+    for file in &module.files {
+        if let Err(e) = fs::remove_file(file) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove {}: {}", file.display(), e);
+            }
+        }
     }
+    if module.dir != em.temp_dir() {
+        let _ = fs::remove_dir_all(&module.dir);
+    }
+}
 
-    let out_chmod = match Command::new("chmod")
-            .args(&["+x", filename])
-            .output() {
-                Ok(o) => o,
-                Err(_) => {
-                    error!("Failed to set permissions");
-                    return Some(ResultMessage::new(ResultCode::InternalError, None));
-                }
+
+/// Launch a new instance of the module with index `source`
+///
+/// The instance gets its own index, files and options. Its files are copied from the store
+/// if `source` was loaded by hash, else from the files of `source`.
+pub fn instantiate_module(em : &EventManager, source : u16, mut options : LoadOptions)
+        -> Result<ResultMessage, CommandError> {
+    let (loader, artifacts, files) = match em.modules.lock().unwrap().get(&source) {
+        Some(m) => (m.get_loader(), m.get_options().artifacts.clone(), m.get_files().clone()),
+        None    => return Err(CommandError::UnknownModule(source))
     };
 
-    match out_chmod.status.code() {
-        Some(x) if x == 0 => (),
-        _ => {
-                error!("Chmod failed");
-                return Some(ResultMessage::new(ResultCode::InternalError, None));
-            }
+    let loader = get_loader(em, loader).unwrap(); // registered when loading source
+    match artifacts.is_empty() {
+        true    => load(em, loader, Source::Module(files), options),
+        false   => {
+            options.artifacts = artifacts;
+            load(em, loader, Source::Store, options)
+        }
+    }
+}
+
+
+/// Stop module `ind` and remove its files
+pub fn unload_module(em : &EventManager, ind : u16) -> Result<ResultMessage, CommandError> {
+    let mut module = match em.modules.lock().unwrap().remove(&ind) {
+        Some(m) => m,
+        None    => return Err(CommandError::UnknownModule(ind))
+    };
+
+    module.kill();
+
+    if let Some(id) = module.get_options().module_id {
+        em.endpoints.lock().unwrap().remove(&id);
+    }
+
+    remove_files(em, module.get_files());
+
+    debug!("Module {} unloaded", ind);
+    Ok(ResultMessage::new(ResultCode::Ok, None))
+}
+
+
+/// Kill module `ind` and launch it again with the same files and options
+///
+/// If the module does not become ready, the new process is killed but the module stays
+/// loaded, so that it can be restarted again.
+pub fn restart_module(em : &EventManager, ind : u16) -> Result<ResultMessage, CommandError> {
+    let (loader, files, options) = match em.modules.lock().unwrap().get_mut(&ind) {
+        Some(m) => {
+            m.kill();
+            (m.get_loader(), m.get_files().clone(), m.get_options().clone())
+        },
+        None    => return Err(CommandError::UnknownModule(ind))
     };
 
-    match Command::new(filename).spawn() {
-        Ok(module)  => {
-            let mut modules = MODULES.lock().unwrap();
-            modules.push(module);
+    // the new process has to register again
+    if let Some(id) = options.module_id {
+        em.endpoints.lock().unwrap().remove(&id);
+    }
+
+    let loader = get_loader(em, loader).unwrap(); // registered when loading
+    let token = new_token().map_err(|e|
+        CommandError::Internal(format!("Failed to generate a token: {}", e)))?;
+    let mut child = spawn_module(em, loader, &files, &options, &token).map_err(|e|
+        CommandError::Internal(format!("program failed to start: {}", e)))?;
+
+    match em.modules.lock().unwrap().get_mut(&ind) {
+        Some(m) => m.restart(em, child, token),
+        None    => {
+            // unloaded in the meantime
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CommandError::UnknownModule(ind));
+        }
+    }
+
+    if let (Some(timeout), Some(id)) = (options.ready_timeout, options.module_id) {
+        if let Err(e) = wait_until_ready(em, ind, id, timeout) {
+            if let Some(module) = em.modules.lock().unwrap().get_mut(&ind) {
+                module.kill();
+            }
+
+            return Err(e);
+        }
+    }
+
+    debug!("Module {} restarted", ind);
+    Ok(ResultMessage::new(ResultCode::Ok, None))
+}
+
+
+fn spawn_module(em : &EventManager, loader : &dyn ModuleLoader, module : &ModuleFiles,
+        options : &LoadOptions, token : &str) -> std::io::Result<Child> {
+    let mut command = loader.command(em, module, options);
+    command.args(&options.args);
+    command.env("EM_MODULE_SOCKET", socket_path(&module.dir, module.index));
+    command.env("EM_MODULE_TOKEN", token);
+    command.envs(options.env.iter().map(|(k, v)| (k, v)));
+
+    // capture output, it can be retrieved with GetModuleLogs
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    command.spawn()
+}
+
+
+fn start_module(em : &EventManager, loader : &dyn ModuleLoader, module : ModuleFiles,
+        options : LoadOptions) -> Result<ResultMessage, CommandError> {
+    let ind = module.index;
+
+    // the module has to register, an older registration of its ID does not count
+    if let Some(id) = options.module_id {
+        em.endpoints.lock().unwrap().remove(&id);
+    }
+
+    // run module
+    let spawned = new_token().and_then(|token|
+        spawn_module(em, loader, &module, &options, &token).map(|child| (child, token)));
+
+    match spawned {
+        Ok((child, token))  => {
+            let ready = (options.ready_timeout, options.module_id);
+            let mut modules = em.modules.lock().unwrap();
+            modules.insert(ind, Module::new(em, loader.name(), module, options, child, token));
+            drop(modules);
             debug!("Module started successfully");
-            Some(ResultMessage::new(ResultCode::Ok, None))
+
+            if let (Some(timeout), Some(id)) = ready {
+                if let Err(e) = wait_until_ready(em, ind, id, timeout) {
+                    if let Some(mut module) = em.modules.lock().unwrap().remove(&ind) {
+                        module.kill();
+                        remove_files(em, module.get_files());
+                    }
+
+                    return Err(e);
+                }
+                debug!("Module {} is ready", ind);
+            }
+
+            Ok(ResultMessage::new(ResultCode::Ok, Some(LoadSMResult { index : ind }.encode())))
         }
-        Err(_)      => {
-            error!("program failed to start");
-            Some(ResultMessage::new(ResultCode::InternalError, None))
+        Err(e)              => {
+            remove_files(em, &module);
+            Err(CommandError::Internal(format!("program failed to start: {}", e)))
         }
     }
 }
+
+
+/// Wait until module `ind` (with ID `id`) registers or listens on its port
+///
+/// Returns a diagnostic if the module exits or does not become ready within `timeout`
+fn wait_until_ready(em : &EventManager, ind : u16, id : u16, timeout : Duration)
+        -> Result<(), CommandError> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let status = match em.modules.lock().unwrap().get_mut(&ind) {
+            Some(m) => m.try_wait(),
+            None    => return Err(CommandError::ModuleExited(ind,
+                        "module has been removed".to_string()))
+        };
+
+        if let Some(status) = status {
+            let logs = match em.modules.lock().unwrap().get(&ind) {
+                Some(m) => m.get_logs(),
+                None    => return Err(CommandError::ModuleExited(ind,
+                            format!("module exited during startup ({})", status)))
+            };
+
+            // give the capture threads some time to read what is left in the pipes
+            logs.wait_closed(READY_POLL_INTERVAL);
+            let (lines, _) = logs.tail(READY_DIAGNOSTIC_LINES);
+            let output : Vec<String> = lines.into_iter().map(|l| l.line).collect();
+
+            return Err(CommandError::ModuleExited(ind, format!(
+                "module exited during startup ({}). Last output:\n{}", status, output.join("\n"))));
+        }
+
+        if is_registered(em, id) {
+            return Ok(());
+        }
+
+        let ready = get_endpoint(em, id).map(|e| is_listening(&e, READY_POLL_INTERVAL));
+        if ready == Some(true) {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(CommandError::Timeout(format!(
+                "module {} did not register nor listen on its port after {:?}", ind, timeout)));
+        }
+
+        thread::sleep(READY_POLL_INTERVAL);
+    }
+}
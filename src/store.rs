@@ -0,0 +1,132 @@
+use std::fs;
+use std::io::{self, prelude::*};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::convert::{TryFrom, TryInto};
+
+use sha2::{Sha256, Digest};
+use tempfile::NamedTempFile;
+
+use crate::EventManager;
+use crate::protocol::Stream;
+
+
+pub type Hash = [u8; 32];
+
+
+/// Path of an artifact in the store, if it exists
+pub fn get(em : &EventManager, hash : &Hash) -> Option<PathBuf> {
+    let path = em.store_dir.join(hex::encode(hash));
+
+    match path.is_file() {
+        true    => Some(path),
+        false   => None
+    }
+}
+
+
+/// Read `size` bytes from `stream` and add them to the store
+pub fn put_from_stream(em : &EventManager, stream : &mut dyn Stream, size : u32)
+        -> io::Result<Hash> {
+    let tmp = NamedTempFile::new_in(&em.store_dir)?;
+    let mut file = tmp.as_file();
+    let mut hasher = Sha256::new();
+
+    // read data
+    let mut buf : [u8; 1024] = [0; 1024];
+    let mut size_left = usize::try_from(size).unwrap(); // should never panic
+    while size_left > 0 {
+        let min = std::cmp::min(size_left, 1024);
+
+        stream.read_exact(&mut buf[..min])?;
+        file.write_all(&buf[..min])?;
+        hasher.update(&buf[..min]);
+
+        size_left -= min;
+    }
+
+    let hash : Hash = hasher.finalize().into();
+    persist(em, tmp, &hash)?;
+
+    Ok(hash)
+}
+
+
+/// Add everything that can be read from `reader` to the store, up to `limit` bytes
+pub fn put_from_reader<R : Read>(em : &EventManager, reader : &mut R, limit : u64)
+        -> io::Result<Hash> {
+    let tmp = NamedTempFile::new_in(&em.store_dir)?;
+    let mut hasher = Sha256::new();
+    let mut buf : [u8; 1024] = [0; 1024];
+    let mut size : u64 = 0;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0)                                               => break,
+            Ok(n)                                               => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted    => continue,
+            Err(e)                                              => return Err(e)
+        };
+
+        size += n as u64;
+        if size > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Artifact is too large"));
+        }
+
+        tmp.as_file().write_all(&buf[..n])?;
+        hasher.update(&buf[..n]);
+    }
+
+    let hash : Hash = hasher.finalize().into();
+    persist(em, tmp, &hash)?;
+
+    Ok(hash)
+}
+
+
+/// Make a copy of an artifact at `dest`
+pub fn copy_to(em : &EventManager, hash : &Hash, dest : &Path) -> io::Result<()> {
+    match get(em, hash) {
+        Some(src)   => {
+            // a copy, that the module cannot use to modify the store
+            fs::copy(&src, dest)?;
+            fs::set_permissions(dest, fs::Permissions::from_mode(0o644))
+        },
+        None        => Err(io::Error::new(io::ErrorKind::NotFound,
+                        format!("Artifact {} not found", hex::encode(hash))))
+    }
+}
+
+
+/// Hashes of the artifacts in the store
+pub fn list(em : &EventManager) -> io::Result<Vec<Hash>> {
+    let mut hashes = Vec::new();
+
+    for entry in fs::read_dir(&em.store_dir)? {
+        let name = entry?.file_name();
+
+        // skip the temporary files of uploads in progress
+        if let Some(Ok(hash)) = name.to_str().map(hex::decode) {
+            if let Ok(hash) = hash.as_slice().try_into() {
+                hashes.push(hash);
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+
+/// Remove an artifact from the store
+pub fn remove(em : &EventManager, hash : &Hash) -> io::Result<()> {
+    fs::remove_file(em.store_dir.join(hex::encode(hash)))
+}
+
+
+/// Give a complete artifact its name in the store. Artifacts are read-only, and replaced
+/// atomically if they already exist
+fn persist(em : &EventManager, tmp : NamedTempFile, hash : &Hash) -> io::Result<()> {
+    tmp.as_file().set_permissions(fs::Permissions::from_mode(0o444))?;
+    tmp.persist(em.store_dir.join(hex::encode(hash))).map_err(|e| e.error)?;
+    Ok(())
+}
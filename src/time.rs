@@ -1,9 +1,9 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::MEASURE_TIME;
+use crate::EventManager;
 
-pub fn measure_time(msg : &str) {
-    if !*MEASURE_TIME {
+pub fn measure_time(em : &EventManager, msg : &str) {
+    if !em.config.measure_time {
         return;
     }
 
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::store::{self, Hash};
+use crate::protocol::Compression;
+use crate::EventManager;
+
+
+// artifacts are limited to the size of a plain LoadSM upload
+const MAX_ARTIFACT_SIZE : u64 = u32::MAX as u64;
+
+
+pub enum UploadError {
+    UnknownUpload(u32),
+    /// the chunk does not start where the previous one ended (contains the expected offset)
+    WrongOffset(u32),
+    TooLarge,
+    /// the declared size is above the limit of the EM (contains the limit)
+    SizeLimit(u32),
+    /// the EM has too many uploads in progress (contains the limit)
+    TooMany(usize),
+    Incomplete(u32),
+    Io(io::Error)
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UploadError::UnknownUpload(id)  => write!(f, "Unknown upload: {}", id),
+            UploadError::WrongOffset(off)   => write!(f, "Wrong chunk offset, expected {}", off),
+            UploadError::TooLarge           => write!(f, "Upload exceeds its declared size"),
+            UploadError::SizeLimit(max)     => write!(f, "Uploads are limited to {} bytes", max),
+            UploadError::TooMany(max)       => write!(f, "Too many uploads in progress ({})", max),
+            UploadError::Incomplete(off)    => write!(f, "Upload is incomplete ({} bytes)", off),
+            UploadError::Io(e)              => write!(f, "{}", e)
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e : io::Error) -> UploadError {
+        UploadError::Io(e)
+    }
+}
+
+
+/// A chunked upload in progress
+///
+/// Chunks are appended to a file as they arrive, so that an interrupted upload can be
+/// resumed from the last acknowledged offset. The file is removed with the upload.
+struct Upload {
+    size : u32,
+    compression : Compression,
+    received : u32,
+    file : File,
+    path : PathBuf,
+    last_active : Instant
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Uploads in progress. Each one has its own lock, so that a slow disk only delays the
+/// uploads it writes
+#[derive(Default)]
+pub struct Uploads {
+    next_id : u32,
+    uploads : HashMap<u32, Arc<Mutex<Upload>>>
+}
+
+impl Uploads {
+    /// Forget the uploads that nobody used for longer than `ttl`
+    fn expire(&mut self, ttl : Duration) {
+        self.uploads.retain(|id, upload| match upload.try_lock() {
+            Ok(u) if u.last_active.elapsed() > ttl  => {
+                debug!("Upload {} expired at {} bytes", id, u.received);
+                false
+            },
+            _                                       => true
+        });
+    }
+}
+
+
+/// Start a new upload of `size` bytes (as transferred, i.e. possibly compressed)
+pub fn begin(em : &EventManager, size : u32, compression : Compression)
+        -> Result<u32, UploadError> {
+    if size > em.config.max_upload_size {
+        return Err(UploadError::SizeLimit(em.config.max_upload_size));
+    }
+
+    let mut uploads = em.uploads.lock().unwrap();
+
+    uploads.expire(Duration::from_secs(em.config.upload_ttl));
+    if uploads.uploads.len() >= em.config.max_uploads {
+        return Err(UploadError::TooMany(em.config.max_uploads));
+    }
+
+    let id = uploads.next_id;
+    uploads.next_id = uploads.next_id.wrapping_add(1);
+
+    let dir = em.temp_dir().join("uploads");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.part", id));
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+
+    let upload = Upload { size, compression, received : 0, file, path, last_active : Instant::now() };
+    uploads.uploads.insert(id, Arc::new(Mutex::new(upload)));
+
+    Ok(id)
+}
+
+
+fn get(em : &EventManager, id : u32) -> Result<Arc<Mutex<Upload>>, UploadError> {
+    match em.uploads.lock().unwrap().uploads.get(&id) {
+        Some(u) => Ok(u.clone()),
+        None    => Err(UploadError::UnknownUpload(id))
+    }
+}
+
+
+/// Number of bytes received so far
+pub fn status(em : &EventManager, id : u32) -> Result<u32, UploadError> {
+    let upload = get(em, id)?;
+    let mut upload = upload.lock().unwrap();
+
+    upload.last_active = Instant::now();
+    Ok(upload.received)
+}
+
+
+/// Append a chunk starting at `offset`, returns the number of bytes received so far
+pub fn write_chunk(em : &EventManager, id : u32, offset : u32, data : &[u8])
+        -> Result<u32, UploadError> {
+    let upload = get(em, id)?;
+    let mut upload = upload.lock().unwrap();
+    upload.last_active = Instant::now();
+
+    if offset != upload.received {
+        return Err(UploadError::WrongOffset(upload.received));
+    }
+
+    if data.len() as u64 > (upload.size - upload.received) as u64 {
+        return Err(UploadError::TooLarge);
+    }
+
+    // a previous session could have written (but not acknowledged) more data
+    upload.file.seek(SeekFrom::Start(offset as u64))?;
+    upload.file.write_all(data)?;
+    upload.file.sync_data()?;
+    upload.received += data.len() as u32;
+
+    Ok(upload.received)
+}
+
+
+/// Complete an upload: decompress it and add it to the store
+pub fn finish(em : &EventManager, id : u32) -> Result<Hash, UploadError> {
+    let upload = get(em, id)?;
+    let mut upload = upload.lock().unwrap();
+
+    if upload.received != upload.size {
+        return Err(UploadError::Incomplete(upload.received));
+    }
+
+    // the upload may have been finished by another request in the meantime
+    if em.uploads.lock().unwrap().uploads.remove(&id).is_none() {
+        return Err(UploadError::UnknownUpload(id));
+    }
+
+    upload.file.seek(SeekFrom::Start(0))?;
+    let data = (&upload.file).take(upload.size as u64);
+
+    let mut reader : Box<dyn Read + '_> = match upload.compression {
+        Compression::None   => Box::new(data),
+        Compression::Gzip   => Box::new(flate2::read::GzDecoder::new(data)),
+        Compression::Zstd   => Box::new(zstd::stream::read::Decoder::new(data)?)
+    };
+
+    // the limit prevents a small compressed upload from filling the disk
+    Ok(store::put_from_reader(em, &mut reader, MAX_ARTIFACT_SIZE)?)
+}
+
+
+/// Abandon all the uploads in progress
+pub fn clear(em : &EventManager) {
+    em.uploads.lock().unwrap().uploads.clear();
+}
@@ -0,0 +1,383 @@
+//! Harness for the integration tests: EMs on ephemeral ports and mock modules
+#![allow(dead_code)]
+
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream, SocketAddr, Ipv4Addr};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+
+use event_manager::{Config, EventManager};
+use event_manager::client::{Client, ClientError};
+use event_manager::commands::{ExtCommandCode, ERROR_CODES_FLAG};
+use event_manager::protocol::*;
+
+pub const TIMEOUT : Duration = Duration::from_secs(5);
+
+// entry points of the modules, see `reactive_net::EntrypointID`
+pub const HANDLE_INPUT : u16 = 2;
+pub const HANDLE_HANDLER : u16 = 3;
+
+// commands of `reactive_net::CommandCode`
+pub const ADD_CONNECTION : u8 = 0;
+pub const MODULE_OUTPUT : u8 = 6;
+
+// options of LoadModule, see `protocol::LoadOptions`
+pub const OPTION_MODULE_ID : u8 = 1;
+pub const OPTION_WAIT_READY : u8 = 4;
+pub const OPTION_ARTIFACT : u8 = 5;
+
+/// A native module that prints its token, and does nothing else
+pub const SCRIPT : &[u8] = b"#!/bin/sh\necho \"$EM_MODULE_TOKEN\"\nexec sleep 30\n";
+
+
+/// Configuration of the test EMs, whose mock modules are not started by the EM
+pub fn config() -> Config {
+    let mut config = Config::new(0);
+    config.external_modules = true;
+    config
+}
+
+
+/// An EM running in a background thread
+pub struct TestEm {
+    pub em : Arc<EventManager>,
+    pub client : Client
+}
+
+impl TestEm {
+    pub fn start() -> TestEm {
+        TestEm::with_config(config())
+    }
+
+    /// An EM that runs native modules, without sandbox
+    pub fn native() -> TestEm {
+        let mut config = config();
+        config.default_loader = "native".to_string();
+        TestEm::with_config(config)
+    }
+
+    pub fn with_config(config : Config) -> TestEm {
+        let em = Arc::new(EventManager::bind(config).expect("Failed to start EM"));
+
+        let runner = em.clone();
+        thread::spawn(move || runner.run());
+
+        let mut client = Client::new(SocketAddr::from((Ipv4Addr::LOCALHOST, em.port())));
+        client.set_timeout(Some(TIMEOUT));
+
+        TestEm { em, client }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.em.port()
+    }
+
+    /// Send a ModuleOutput as a module would do, returns the result (if any)
+    pub fn module_output(&self, entry : u16, conn_id : u16, data : &[u8])
+            -> Option<(u8, Vec<u8>)> {
+        let output = ModuleOutput { entry, conn_id, data : data.to_vec() };
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        write_raw_command(&mut stream, MODULE_OUTPUT | ERROR_CODES_FLAG, &output.encode()).unwrap();
+
+        // the EM closes the connection without a result for outputs that go to another EM
+        read_raw_result(&mut stream).ok()
+    }
+
+    /// Send an output from a module, with acknowledgement
+    pub fn module_output_ack(&self, entry : u16, conn_id : u16, data : &[u8]) -> (u8, Vec<u8>) {
+        let output = ModuleOutput { entry, conn_id, data : data.to_vec() };
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let code = ExtCommandCode::ModuleOutputAck as u8 | ERROR_CODES_FLAG;
+        write_raw_command(&mut stream, code, &output.encode()).unwrap();
+
+        read_raw_result(&mut stream).unwrap()
+    }
+
+    /// Send LoadModule with `options` (as tag and value), followed by `files`
+    pub fn load_module(&self, options : &[(u8, &[u8])], files : &[&[u8]]) -> (u8, Vec<u8>) {
+        let mut payload = Vec::new();
+        for (tag, value) in options {
+            payload.push(*tag);
+            payload.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            payload.extend_from_slice(value);
+        }
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let code = ExtCommandCode::LoadModule as u8 | ERROR_CODES_FLAG;
+        write_raw_command(&mut stream, code, &payload).unwrap();
+        for file in files {
+            stream.write_all(&(file.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(file).unwrap();
+        }
+
+        read_raw_result(&mut stream).unwrap()
+    }
+
+    /// Add an artifact to the store with UploadArtifact, returns its hash
+    pub fn upload_artifact(&self, data : &[u8]) -> [u8; 32] {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        // the size and data are not preceded by the length of the payload
+        stream.write_all(&[ExtCommandCode::UploadArtifact as u8 | ERROR_CODES_FLAG]).unwrap();
+        stream.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(data).unwrap();
+
+        let (code, hash) = read_raw_result(&mut stream).unwrap();
+        assert_eq!(code, 0);
+
+        let mut h = [0u8; 32];
+        h.copy_from_slice(&hash);
+        h
+    }
+
+    /// Start a chunked upload, returns its ID
+    pub fn upload_begin(&self, size : u32, compression : u8) -> Result<u32, ClientError> {
+        let mut payload = size.to_be_bytes().to_vec();
+        payload.push(compression);
+
+        let id = self.client.command(ExtCommandCode::UploadBegin as u8, &payload)?;
+        Ok(bytes_to_u32(&id))
+    }
+
+    /// Send `chunks` of upload `id` from `offset` with UploadData, returns the offsets
+    /// acknowledged by the EM. Stops after `limit` chunks, without ending the transfer
+    pub fn upload_data(&self, id : u32, offset : u32, chunks : &[&[u8]], limit : usize)
+            -> Vec<u32> {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&offset.to_be_bytes());
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let code = ExtCommandCode::UploadData as u8 | ERROR_CODES_FLAG;
+        write_raw_command(&mut stream, code, &payload).unwrap();
+
+        let mut acks = Vec::new();
+        for chunk in chunks.iter().take(limit) {
+            write_message_to(&mut stream, chunk).unwrap();
+
+            let (code, ack) = read_raw_result(&mut stream).unwrap();
+            assert_eq!(code, 0);
+            acks.push(bytes_to_u32(&ack));
+        }
+
+        if limit >= chunks.len() {
+            write_message_to(&mut stream, &[]).unwrap();
+            let (code, _) = read_raw_result(&mut stream).unwrap();
+            assert_eq!(code, 0);
+        }
+
+        acks
+    }
+
+    /// Number of bytes of upload `id` received by the EM
+    pub fn upload_status(&self, id : u32) -> Result<u32, ClientError> {
+        let received = self.client.command(ExtCommandCode::UploadStatus as u8, &id.to_be_bytes())?;
+        Ok(bytes_to_u32(&received))
+    }
+
+    /// Complete upload `id`, returns the hash of the artifact
+    pub fn upload_finish(&self, id : u32) -> Result<Vec<u8>, ClientError> {
+        self.client.command(ExtCommandCode::UploadFinish as u8, &id.to_be_bytes())
+    }
+
+    /// Lines written by module `index` so far
+    pub fn module_logs(&self, index : u16) -> Vec<String> {
+        self.logs(index, false)
+    }
+
+    /// Lines written by module `index` with GetModuleLogs. With `follow`, this returns when
+    /// the module closes its output
+    pub fn logs(&self, index : u16, follow : bool) -> Vec<String> {
+        let mut payload = index.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0xff, 0xff, follow as u8]);
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port())).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let code = ExtCommandCode::GetModuleLogs as u8 | ERROR_CODES_FLAG;
+        write_raw_command(&mut stream, code, &payload).unwrap();
+        assert_eq!(read_raw_result(&mut stream).unwrap().0, 0);
+
+        // messages of [<stream (u8)><len (u16)><line>] entries, until an empty one
+        let mut lines = Vec::new();
+        loop {
+            let msg = read_message_from(&mut stream).unwrap();
+            if msg.is_empty() {
+                return lines;
+            }
+
+            let mut rest = &msg[..];
+            while rest.len() >= 3 {
+                let len = bytes_to_u16(&rest[1..3]) as usize;
+                lines.push(String::from_utf8_lossy(&rest[3..3 + len]).into_owned());
+                rest = &rest[3 + len..];
+            }
+        }
+    }
+
+    /// Wait until module `index` has written `n` lines
+    pub fn wait_logs(&self, index : u16, n : usize) -> Vec<String> {
+        let start = std::time::Instant::now();
+
+        loop {
+            let lines = self.module_logs(index);
+            if lines.len() >= n || start.elapsed() > TIMEOUT {
+                return lines;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+
+/// A call received by a mock module
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub entry : u16,
+    pub data : Vec<u8>
+}
+
+#[derive(Default)]
+struct Calls {
+    calls : Mutex<Vec<Call>>,
+    cond : Condvar,
+    /// set to keep the module busy with the last call
+    held : Mutex<bool>,
+    released : Condvar
+}
+
+/// In-process server that speaks the module side of the protocol
+///
+/// It records the entry points called by the EM and replies with the data it received
+pub struct MockModule {
+    pub id : u16,
+    calls : Arc<Calls>
+}
+
+impl MockModule {
+    /// Start a module and register its endpoint as module `id` of `em`
+    pub fn start(em : &TestEm, id : u16) -> MockModule {
+        MockModule::register(em, id, None).expect("Failed to register module")
+    }
+
+    /// Start a module and register it with `token`, if any
+    pub fn register(em : &TestEm, id : u16, token : Option<&str>)
+            -> Result<MockModule, ClientError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let calls = Arc::new(Calls::default());
+
+        let recorder = calls.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s)   => serve(s, &recorder),
+                    Err(_)  => break
+                }
+            }
+        });
+
+        // endpoint is: [<type (u8)><port (u16)>]
+        let mut endpoint = vec![0];
+        endpoint.extend_from_slice(&port.to_be_bytes());
+        send_registration(em, id, &endpoint, token)?;
+
+        Ok(MockModule { id, calls })
+    }
+
+    /// Start a module listening on the Unix socket `path`, and register it
+    pub fn register_unix(em : &TestEm, id : u16, path : &Path)
+            -> Result<MockModule, ClientError> {
+        let listener = UnixListener::bind(path).unwrap();
+        let calls = Arc::new(Calls::default());
+
+        let recorder = calls.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s)   => serve(s, &recorder),
+                    Err(_)  => break
+                }
+            }
+        });
+
+        // endpoint is: [<type (u8)><len (u16)><path>]
+        let path = path.to_str().unwrap().as_bytes();
+        let mut endpoint = vec![1];
+        endpoint.extend_from_slice(&(path.len() as u16).to_be_bytes());
+        endpoint.extend_from_slice(path);
+        send_registration(em, id, &endpoint, None)?;
+
+        Ok(MockModule { id, calls })
+    }
+
+    /// Wait until the module received at least `n` calls, returns all of them
+    pub fn wait_calls(&self, n : usize, timeout : Duration) -> Vec<Call> {
+        let calls = self.calls.calls.lock().unwrap();
+        let (calls, _) = self.calls.cond.wait_timeout_while(calls, timeout, |c| c.len() < n)
+            .unwrap();
+        calls.clone()
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.calls.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.calls.calls.lock().unwrap().clear();
+    }
+
+    /// Do not reply to the next calls until `release`, as a slow module
+    pub fn hold(&self) {
+        *self.calls.held.lock().unwrap() = true;
+    }
+
+    pub fn release(&self) {
+        *self.calls.held.lock().unwrap() = false;
+        self.calls.released.notify_all();
+    }
+}
+
+fn send_registration(em : &TestEm, id : u16, endpoint : &[u8], token : Option<&str>)
+        -> Result<(), ClientError> {
+    // payload is: [<module ID (u16)><endpoint><token>]
+    let mut payload = id.to_be_bytes().to_vec();
+    payload.extend_from_slice(endpoint);
+    if let Some(token) = token {
+        payload.push(1);
+        payload.extend_from_slice(&(token.len() as u16).to_be_bytes());
+        payload.extend_from_slice(token.as_bytes());
+    }
+
+    em.client.command(ExtCommandCode::RegisterModule as u8, &payload).map(|_| ())
+}
+
+fn serve<S : Read + Write>(mut stream : S, calls : &Calls) {
+    let payload = match read_message_from(&mut stream) {
+        Ok(p) if p.len() >= 2   => p,
+        _                       => return
+    };
+
+    let call = Call { entry : bytes_to_u16(&payload[..2]), data : payload[2..].to_vec() };
+
+    // result is: [<code (u8)><len (u16)><payload>]
+    let mut result = vec![0];
+    result.extend_from_slice(&(call.data.len() as u16).to_be_bytes());
+    result.extend_from_slice(&call.data);
+
+    calls.calls.lock().unwrap().push(call);
+    calls.cond.notify_all();
+
+    drop(calls.released.wait_while(calls.held.lock().unwrap(), |held| *held).unwrap());
+
+    let _ = stream.write_all(&result);
+}
+
@@ -0,0 +1,146 @@
+mod common;
+
+use std::net::{SocketAddrV4, Ipv4Addr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use event_manager::protocol::{Priority, ResultCode, bytes_to_u16, read_raw_result,
+    write_raw_command};
+
+use common::*;
+
+
+type Output = Option<(u8, Vec<u8>)>;
+
+const RESET : u8 = 4;
+
+
+/// An EM with a single worker for the events, kept busy by slow module 2 (connection 7)
+/// until `release`
+fn start_busy() -> (Arc<TestEm>, MockModule, JoinHandle<Output>) {
+    let mut config = config();
+    config.threads = 1;
+
+    let em = Arc::new(TestEm::with_config(config));
+    let _from = MockModule::start(&em, 1);
+    let slow = MockModule::start(&em, 2);
+
+    em.client.add_connection(7, 2, SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port()), true)
+        .unwrap();
+
+    slow.hold();
+    let busy = send(&em, 7);
+    slow.wait_calls(1, TIMEOUT);
+
+    (em, slow, busy)
+}
+
+fn send(em : &Arc<TestEm>, conn_id : u16) -> JoinHandle<Output> {
+    let em = em.clone();
+    thread::spawn(move || em.module_output(HANDLE_INPUT, conn_id, &[1, 2, 3]))
+}
+
+
+#[test]
+fn control_lane() {
+    let (em, slow, busy) = start_busy();
+
+    // no worker is left for the events, but commands are still served
+    let start = Instant::now();
+    em.client.add_connection(8, 3, SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port()), true)
+        .unwrap();
+    em.client.reset().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    slow.release();
+    assert!(busy.join().unwrap().is_some());
+}
+
+
+#[test]
+fn priorities() {
+    let (em, slow, busy) = start_busy();
+    let to = MockModule::start(&em, 3);
+
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port());
+    em.client.add_connection(8, 3, address, true).unwrap();
+    em.client.add_connection(9, 3, address, true).unwrap();
+    em.client.set_connection_priority(8, Priority::Low).unwrap();
+    em.client.set_connection_priority(9, Priority::High).unwrap();
+
+    // the output of the low-priority connection waits for the worker first
+    let low = send(&em, 8);
+    thread::sleep(Duration::from_millis(100));
+    let high = send(&em, 9);
+    thread::sleep(Duration::from_millis(100));
+
+    slow.release();
+    for h in [busy, low, high] {
+        assert!(h.join().unwrap().is_some());
+    }
+
+    let connections : Vec<u8> = to.wait_calls(2, TIMEOUT).iter().map(|c| c.data[1]).collect();
+    assert_eq!(connections, vec![9, 8]);
+}
+
+
+#[test]
+fn follow_and_reset() {
+    let mut config = config();
+    config.default_loader = "native".to_string();
+    config.control_threads = 1;
+    config.command_threads = 1;
+
+    let em = Arc::new(TestEm::with_config(config));
+    let (code, index) = em.load_module(&[], &[SCRIPT]);
+    assert_eq!(code, 0);
+    let index = bytes_to_u16(&index);
+    em.wait_logs(index, 1);
+
+    // following the logs holds a worker until the module exits
+    let follow = {
+        let em = em.clone();
+        thread::spawn(move || em.logs(index, true))
+    };
+    thread::sleep(Duration::from_millis(200));
+    assert!(!follow.is_finished());
+
+    let start = Instant::now();
+    em.client.reset().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // the module was killed
+    assert_eq!(follow.join().unwrap().len(), 1);
+}
+
+
+#[test]
+fn slow_client() {
+    let em = TestEm::start();
+
+    // clients that do not send their command do not hold the listener or the classifier
+    let _idle : Vec<TcpStream> = (0..8)
+        .map(|_| TcpStream::connect((Ipv4Addr::LOCALHOST, em.port())).unwrap()).collect();
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    em.client.reset().unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+
+#[test]
+fn late_command() {
+    let mut config = config();
+    config.classify_timeout = 100;
+    let em = TestEm::with_config(config);
+
+    // a command that arrives after the timeout is still served
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, em.port())).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    write_raw_command(&mut stream, RESET, &[]).unwrap();
+    assert_eq!(read_raw_result(&mut stream).unwrap(), (ResultCode::Ok as u8, vec![]));
+}
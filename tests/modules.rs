@@ -0,0 +1,190 @@
+mod common;
+
+use std::fs;
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+use event_manager::Config;
+use event_manager::client::ClientError;
+use event_manager::commands::ExtCommandCode;
+use event_manager::protocol::{ResultCode, bytes_to_u16};
+
+use common::*;
+
+
+fn load_script(em : &TestEm, id : u16) -> u16 {
+    let (code, index) = em.load_module(&[(OPTION_MODULE_ID, &id.to_be_bytes())], &[SCRIPT]);
+    assert_eq!(code, 0);
+    bytes_to_u16(&index)
+}
+
+fn failed(res : Result<MockModule, ClientError>) -> ResultCode {
+    match res {
+        Err(ClientError::Failed(code, _))   => code,
+        Err(e)                              => panic!("Unexpected error: {}", e),
+        Ok(_)                               => panic!("Registration succeeded")
+    }
+}
+
+
+#[test]
+fn register_with_token() {
+    let em = TestEm::native();
+    let index = load_script(&em, 5);
+    let token = em.wait_logs(index, 1).remove(0);
+
+    // only the process started by the EM can register as module 5
+    assert_eq!(failed(MockModule::register(&em, 5, None)), ResultCode::Unauthorized);
+    assert_eq!(failed(MockModule::register(&em, 5, Some("0123"))), ResultCode::Unauthorized);
+    let module = MockModule::register(&em, 5, Some(&token)).unwrap();
+
+    assert_eq!(em.client.call_entrypoint(5, 4, &[1]).unwrap(), vec![1]);
+    assert_eq!(module.calls().len(), 1);
+}
+
+
+#[test]
+fn register_unknown() {
+    let mut config = Config::new(0);
+    config.default_loader = "native".to_string();
+    let em = TestEm::with_config(config);
+
+    // without external modules, only the modules started by the EM can register, including
+    // the ones loaded without ID
+    assert_eq!(failed(MockModule::register(&em, 9, None)), ResultCode::Unauthorized);
+
+    let (code, index) = em.load_module(&[], &[SCRIPT]);
+    assert_eq!(code, 0);
+    let index = bytes_to_u16(&index);
+    let token = em.wait_logs(index, 1).remove(0);
+
+    assert_eq!(failed(MockModule::register(&em, index, None)), ResultCode::Unauthorized);
+    assert!(MockModule::register(&em, index, Some(&token)).is_ok());
+}
+
+
+#[test]
+fn register_twice() {
+    let em = TestEm::start();
+    let _module = MockModule::start(&em, 9);
+
+    assert_eq!(failed(MockModule::register(&em, 9, None)), ResultCode::BadRequest);
+}
+
+
+#[test]
+fn store() {
+    let em = TestEm::native();
+    let hash = em.upload_artifact(SCRIPT);
+
+    let (code, index) = em.load_module(&[(OPTION_ARTIFACT, &hash)], &[]);
+    assert_eq!(code, 0);
+    let index = bytes_to_u16(&index);
+
+    // the module gets its own copy, the store keeps a read-only one
+    let stored = em.em.store_dir().join(hex::encode(hash));
+    let copy = em.em.temp_dir().join(format!("sm{}", index));
+    assert_eq!(fs::metadata(&stored).unwrap().permissions().mode() & 0o777, 0o444);
+    assert_ne!(fs::metadata(&stored).unwrap().ino(), fs::metadata(&copy).unwrap().ino());
+
+    // artifacts of loaded modules are kept
+    match em.client.remove_artifacts(Some(&hash)) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::BadRequest),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+    assert_eq!(em.client.remove_artifacts(None).unwrap(), 0);
+
+    em.client.command(ExtCommandCode::UnloadSM as u8, &index.to_be_bytes()).unwrap();
+    assert_eq!(em.client.remove_artifacts(None).unwrap(), 1);
+    assert!(!stored.exists());
+}
+
+
+#[test]
+fn instantiate_without_store() {
+    let em = TestEm::native();
+    let index = load_script(&em, 5);
+
+    // files sent with the command are not kept in the store
+    assert_eq!(fs::read_dir(em.em.store_dir()).unwrap().count(), 0);
+
+    // so instances copy the files of their source
+    let instance = em.client.command(ExtCommandCode::InstantiateSM as u8,
+        &index.to_be_bytes()).unwrap();
+    let instance = bytes_to_u16(&instance);
+    assert_ne!(instance, index);
+    assert_eq!(em.wait_logs(instance, 1).len(), 1);
+}
+
+
+/// A port that module `id` of `em` could listen on, returns the ID and the listener
+fn listen(em : &TestEm) -> (u16, TcpListener) {
+    (1..1000).find_map(|id| TcpListener::bind((Ipv4Addr::LOCALHOST, em.port() + id)).ok()
+        .map(|l| (id, l))).unwrap()
+}
+
+fn restart(em : &TestEm, index : u16) -> Result<Vec<u8>, ClientError> {
+    em.client.command(ExtCommandCode::RestartSM as u8, &index.to_be_bytes())
+}
+
+
+#[test]
+fn ready() {
+    let em = TestEm::native();
+    let (id, listener) = listen(&em);
+    let options : &[(u8, &[u8])] = &[(OPTION_MODULE_ID, &id.to_be_bytes()),
+        (OPTION_WAIT_READY, &500u32.to_be_bytes())];
+
+    // the module is ready once its port is open
+    let (code, index) = em.load_module(options, &[SCRIPT]);
+    assert_eq!(code, 0);
+    let index = bytes_to_u16(&index);
+    restart(&em, index).unwrap();
+
+    // a restarted module that does not become ready is kept
+    drop(listener);
+    match restart(&em, index) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::Timeout),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+    assert!(!em.wait_logs(index, 1).is_empty());
+}
+
+
+#[test]
+fn exit_during_startup() {
+    let em = TestEm::native();
+    let options : &[(u8, &[u8])] = &[(OPTION_MODULE_ID, &3u16.to_be_bytes()),
+        (OPTION_WAIT_READY, &5000u32.to_be_bytes())];
+
+    let (code, msg) = em.load_module(options, &[b"#!/bin/sh\necho missing key\nexit 1\n"]);
+    assert_eq!(code, ResultCode::ModuleUnavailable as u8);
+    assert!(String::from_utf8(msg).unwrap().ends_with("missing key"));
+}
+
+
+#[test]
+fn unix_socket() {
+    let em = TestEm::start();
+    let module = MockModule::register_unix(&em, 3, &em.em.temp_dir().join("mock.sock")).unwrap();
+
+    assert_eq!(em.client.call_entrypoint(3, 4, &[1, 2]).unwrap(), vec![1, 2]);
+    assert_eq!(module.calls().len(), 1);
+
+    // sockets outside the directory of the EM are refused
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(failed(MockModule::register_unix(&em, 4, &dir.path().join("mock.sock"))),
+        ResultCode::IllegalPayload);
+}
+
+
+#[test]
+fn hello() {
+    let em = TestEm::native();
+    let caps = em.client.hello().unwrap();
+
+    assert_eq!(caps.version, 1);
+    assert!(caps.loaders.contains(&"native".to_string()));
+    assert!(caps.commands.contains(&(ExtCommandCode::RemoveArtifacts as u8)));
+    assert!(caps.features.contains(&"load-by-hash".to_string()));
+}
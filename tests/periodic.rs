@@ -0,0 +1,68 @@
+mod common;
+
+use std::net::{SocketAddrV4, Ipv4Addr};
+use std::thread;
+use std::time::Duration;
+
+use event_manager::protocol::{ResultCode, Priority};
+
+use common::*;
+
+
+fn start_with_periodic_tasks() -> TestEm {
+    let mut config = config();
+    config.periodic_tasks = true;
+
+    TestEm::with_config(config)
+}
+
+
+#[test]
+fn periodic_task() {
+    let em = start_with_periodic_tasks();
+    let module = MockModule::start(&em, 1);
+
+    em.client.register_entrypoint(1, 6, 100, Priority::Normal).unwrap();
+
+    let calls = module.wait_calls(3, TIMEOUT);
+    assert!(calls.len() >= 3);
+    assert!(calls.iter().all(|c| *c == Call { entry : 6, data : vec![] }));
+}
+
+
+#[test]
+fn periodic_tasks_disabled() {
+    let em = TestEm::start();
+    let module = MockModule::start(&em, 1);
+
+    em.client.register_entrypoint(1, 6, 50, Priority::Normal).unwrap();
+
+    thread::sleep(Duration::from_millis(300));
+    assert!(module.calls().is_empty());
+}
+
+
+#[test]
+fn reset() {
+    let em = start_with_periodic_tasks();
+    let module = MockModule::start(&em, 1);
+    let to = MockModule::start(&em, 2);
+
+    em.client.register_entrypoint(1, 6, 50, Priority::Normal).unwrap();
+    em.client.add_connection(7, 2, SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port()), true)
+        .unwrap();
+    module.wait_calls(1, TIMEOUT);
+
+    em.client.reset().unwrap();
+
+    // calls that were already in flight can still arrive
+    thread::sleep(Duration::from_millis(100));
+    module.clear();
+    thread::sleep(Duration::from_millis(300));
+    assert!(module.calls().is_empty());
+
+    // connections are gone too
+    let (code, _) = em.module_output_ack(HANDLE_INPUT, 7, &[1, 2, 3]);
+    assert_eq!(code, ResultCode::UnknownConnection as u8);
+    assert!(to.calls().is_empty());
+}
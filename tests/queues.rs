@@ -0,0 +1,99 @@
+mod common;
+
+use std::net::{SocketAddrV4, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use event_manager::Overflow;
+use event_manager::protocol::ResultCode;
+
+use common::*;
+
+
+/// An EM with queues of 2 outputs, and a slow module 2 reached through connection 7.
+/// Returns once the module is busy with a first output
+fn start_with_queues(overflow : Overflow) -> (TestEm, MockModule, MockModule) {
+    let mut config = config();
+    config.queue_size = 2;
+    config.queue_overflow = overflow;
+
+    let em = TestEm::with_config(config);
+    let from = MockModule::start(&em, 1);
+    let to = MockModule::start(&em, 2);
+
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port());
+    em.client.add_connection(7, 2, address, true).unwrap();
+
+    to.hold();
+    assert_eq!(em.module_output(HANDLE_INPUT, 7, &[1]), Some((0, vec![])));
+    assert_eq!(to.wait_calls(1, TIMEOUT).len(), 1);
+
+    // the queue is full
+    for i in 2..4 {
+        assert_eq!(em.module_output(HANDLE_INPUT, 7, &[i]), Some((0, vec![])));
+    }
+
+    (em, from, to)
+}
+
+fn received(module : &MockModule, n : usize) -> Vec<u8> {
+    module.wait_calls(n, TIMEOUT).iter().map(|c| c.data[2]).collect()
+}
+
+
+#[test]
+fn reject() {
+    let (em, _from, to) = start_with_queues(Overflow::Reject);
+
+    let (code, _) = em.module_output(HANDLE_INPUT, 7, &[4]).unwrap();
+    assert_eq!(ResultCode::from_u8(code), Some(ResultCode::QueueFull));
+
+    let stats = em.client.queue_stats(false).unwrap();
+    assert_eq!(stats.queues.len(), 1);
+    assert_eq!((stats.queues[0].module, stats.queues[0].depth), (2, 2));
+    assert_eq!((stats.queues[0].max_depth, stats.queues[0].rejected), (2, 1));
+
+    to.release();
+    assert_eq!(received(&to, 3), vec![1, 2, 3]);
+}
+
+
+#[test]
+fn drop_oldest() {
+    let (em, _from, to) = start_with_queues(Overflow::DropOldest);
+
+    assert_eq!(em.module_output(HANDLE_INPUT, 7, &[4]), Some((0, vec![])));
+
+    to.release();
+    assert_eq!(received(&to, 3), vec![1, 3, 4]);
+
+    let stats = em.client.queue_stats(true).unwrap();
+    assert_eq!(stats.queues[0].dropped, 1);
+    assert_eq!(em.client.queue_stats(false).unwrap().queues[0].dropped, 0);
+
+    // the dropped output is a dead letter
+    let letters = em.client.dead_letters(false).unwrap();
+    assert_eq!(letters.undelivered, vec![(7, 1)]);
+    assert_eq!(letters.events[0].code, ResultCode::QueueFull);
+}
+
+
+#[test]
+fn block() {
+    let (em, _from, to) = start_with_queues(Overflow::Block);
+
+    let em = Arc::new(em);
+    let sender = {
+        let em = em.clone();
+        thread::spawn(move || em.module_output(HANDLE_INPUT, 7, &[4]))
+    };
+
+    // the sender waits until the module takes the next output
+    thread::sleep(Duration::from_millis(200));
+    assert!(!sender.is_finished());
+
+    to.release();
+    assert_eq!(sender.join().unwrap(), Some((0, vec![])));
+    assert_eq!(received(&to, 4), vec![1, 2, 3, 4]);
+}
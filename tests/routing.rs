@@ -0,0 +1,382 @@
+mod common;
+
+use std::io::prelude::*;
+use std::net::{SocketAddrV4, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use event_manager::client::ClientError;
+use event_manager::commands::ExtCommandCode;
+use event_manager::protocol::{ResultCode, DeadLetters, DeliveryPolicy, ConnectionOrdering,
+    AddConnection, SetConnectionPolicy, ModuleOutput, Payload, bytes_to_u16, write_raw_command};
+
+use common::*;
+
+
+fn em_address(em : &TestEm) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, em.port())
+}
+
+
+#[test]
+fn call_entrypoint() {
+    let em = TestEm::start();
+    let module = MockModule::start(&em, 1);
+
+    let result = em.client.call_entrypoint(1, 5, &[1, 2, 3]).unwrap();
+
+    assert_eq!(result, vec![1, 2, 3]);
+    assert_eq!(module.calls(), vec![Call { entry : 5, data : vec![1, 2, 3] }]);
+}
+
+
+#[test]
+fn unload_unknown_module() {
+    let em = TestEm::start();
+
+    match em.client.command(ExtCommandCode::UnloadSM as u8, &9u16.to_be_bytes()) {
+        Err(ClientError::Failed(code, msg)) => {
+            assert_eq!(code, ResultCode::UnknownModule);
+            assert_eq!(String::from_utf8(msg).unwrap(), "Unknown module 9");
+        },
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn legacy_result_codes() {
+    let mut em = TestEm::start();
+
+    // callers that do not set the flag only get the codes of reactive_net
+    em.client.set_error_codes(false);
+    match em.client.command(ExtCommandCode::UnloadSM as u8, &9u16.to_be_bytes()) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::BadRequest),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+
+    let caps = em.client.hello().unwrap();
+    assert!(caps.features.iter().any(|f| f == "error-codes"));
+
+    em.client.set_error_codes(true);
+    match em.client.command(ExtCommandCode::UnloadSM as u8, &9u16.to_be_bytes()) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::UnknownModule),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn local_output() {
+    let em = TestEm::start();
+    let _from = MockModule::start(&em, 1);
+    let to = MockModule::start(&em, 2);
+
+    em.client.add_connection(7, 2, em_address(&em), true).unwrap();
+
+    let result = em.module_output(HANDLE_INPUT, 7, &[1, 2, 3]);
+
+    assert_eq!(result, Some((0, vec![0, 7, 1, 2, 3])));
+    assert_eq!(to.calls(), vec![Call { entry : HANDLE_INPUT, data : vec![0, 7, 1, 2, 3] }]);
+}
+
+
+#[test]
+fn output_unknown_connection() {
+    let em = TestEm::start();
+    let to = MockModule::start(&em, 2);
+
+    // the output is dropped without a result, but recorded
+    assert_eq!(em.module_output(HANDLE_INPUT, 7, &[1, 2, 3]), None);
+    assert!(to.calls().is_empty());
+
+    let letters = em.client.dead_letters(false).unwrap();
+    assert_eq!(letters.undelivered, vec![(7, 1)]);
+    assert_eq!(letters.events[0].code, ResultCode::UnknownConnection);
+
+    let (code, _) = em.module_output_ack(HANDLE_INPUT, 7, &[1, 2, 3]);
+    assert_eq!(code, ResultCode::UnknownConnection as u8);
+}
+
+
+#[test]
+fn fan_out() {
+    let em_a = TestEm::start();
+    let em_b = TestEm::start();
+    let _from = MockModule::start(&em_a, 1);
+    let local = MockModule::start(&em_a, 2);
+    let remote = MockModule::start(&em_b, 4);
+
+    em_a.client.add_connection(3, 2, em_address(&em_a), true).unwrap();
+    em_a.client.add_connection(3, 4, em_address(&em_b), false).unwrap();
+
+    // the result comes from the first destination
+    let result = em_a.module_output(HANDLE_INPUT, 3, &[1, 2, 3]);
+    assert_eq!(result, Some((0, vec![0, 3, 1, 2, 3])));
+
+    let call = Call { entry : HANDLE_INPUT, data : vec![0, 3, 1, 2, 3] };
+    assert_eq!(local.calls(), vec![call.clone()]);
+    assert_eq!(remote.wait_calls(1, TIMEOUT), vec![call]);
+
+    em_a.client.remove_connection(3, 2, em_address(&em_a), true).unwrap();
+    local.clear();
+    remote.clear();
+
+    assert_eq!(em_a.module_output(HANDLE_INPUT, 3, &[5, 6, 7]), None);
+    assert_eq!(remote.wait_calls(1, TIMEOUT),
+        vec![Call { entry : HANDLE_INPUT, data : vec![0, 3, 5, 6, 7] }]);
+    assert!(local.calls().is_empty());
+
+    // the connection is gone with its last destination
+    em_a.client.remove_connection(3, 4, em_address(&em_b), false).unwrap();
+    match em_a.client.remove_connection(3, 4, em_address(&em_b), false) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::UnknownConnection),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn round_robin() {
+    let em = TestEm::start();
+    let _from = MockModule::start(&em, 1);
+    let first = MockModule::start(&em, 2);
+    let second = MockModule::start(&em, 3);
+
+    em.client.add_connection(5, 2, em_address(&em), true).unwrap();
+    em.client.add_connection(5, 3, em_address(&em), true).unwrap();
+    em.client.set_connection_policy(5, DeliveryPolicy::RoundRobin).unwrap();
+
+    for i in 0..4 {
+        assert_eq!(em.module_output(HANDLE_INPUT, 5, &[i]), Some((0, vec![0, 5, i])));
+    }
+
+    // each output goes to a single replica, in turn
+    let data = |m : &MockModule| m.calls().iter().map(|c| c.data[2]).collect::<Vec<_>>();
+    let (a, b) = (data(&first), data(&second));
+    assert!((a == [0, 2] && b == [1, 3]) || (a == [1, 3] && b == [0, 2]), "{:?} {:?}", a, b);
+}
+
+
+#[test]
+fn primary_backup() {
+    let em = TestEm::start();
+    let _from = MockModule::start(&em, 1);
+    let backup = MockModule::start(&em, 2);
+
+    // nobody listens on the address of the primary
+    let primary = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+    let primary = SocketAddrV4::new(Ipv4Addr::LOCALHOST, primary.port());
+
+    em.client.add_connection(6, 4, primary, false).unwrap();
+    em.client.add_connection(6, 2, em_address(&em), true).unwrap();
+    em.client.set_connection_policy(6, DeliveryPolicy::PrimaryBackup).unwrap();
+
+    for i in 0..2 {
+        assert_eq!(em.module_output(HANDLE_INPUT, 6, &[i]), Some((0, vec![0, 6, i])));
+    }
+    assert_eq!(backup.calls().len(), 2);
+
+    // the output was delivered, so it is not a dead letter
+    assert!(em.client.dead_letters(false).unwrap().undelivered.is_empty());
+
+    match em.client.set_connection_policy(7, DeliveryPolicy::RoundRobin) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::UnknownConnection),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn remote_output() {
+    let em_a = TestEm::start();
+    let em_b = TestEm::start();
+    let _from = MockModule::start(&em_a, 1);
+    let to = MockModule::start(&em_b, 4);
+
+    em_a.client.add_connection(3, 4, em_address(&em_b), false).unwrap();
+
+    // no result for outputs that go to another EM
+    assert_eq!(em_a.module_output(HANDLE_INPUT, 3, &[9, 8, 7, 6]), None);
+
+    let calls = to.wait_calls(1, TIMEOUT);
+    assert_eq!(calls, vec![Call { entry : HANDLE_INPUT, data : vec![0, 3, 9, 8, 7, 6] }]);
+}
+
+
+#[test]
+fn fifo_output() {
+    let em_a = TestEm::start();
+    let em_b = TestEm::start();
+    let _from = MockModule::start(&em_a, 1);
+    let to = MockModule::start(&em_b, 4);
+
+    em_a.client.add_connection(3, 4, em_address(&em_b), false).unwrap();
+    em_a.client.set_connection_ordering(3, ConnectionOrdering::Fifo).unwrap();
+
+    // still no result, but each output has been delivered before the next one is sent
+    for i in 0..5 {
+        assert_eq!(em_a.module_output(HANDLE_INPUT, 3, &[i, 8, 7]), None);
+        assert_eq!(to.calls().len(), i as usize + 1);
+    }
+
+    let data : Vec<u8> = to.calls().iter().map(|c| c.data[2]).collect();
+    assert_eq!(data, vec![0, 1, 2, 3, 4]);
+
+    match em_a.client.set_connection_ordering(4, ConnectionOrdering::Fifo) {
+        Err(ClientError::Failed(code, _))   => assert_eq!(code, ResultCode::UnknownConnection),
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn fifo_arrival_order() {
+    let em = Arc::new(TestEm::start());
+    let _from = MockModule::start(&em, 1);
+    let to = MockModule::start(&em, 2);
+
+    em.client.add_connection(7, 2, em_address(&em), true).unwrap();
+    em.client.set_connection_ordering(7, ConnectionOrdering::Fifo).unwrap();
+
+    // the first output arrives first, but its data comes after the whole second output
+    let mut first = Vec::new();
+    let output = ModuleOutput { entry : HANDLE_INPUT, conn_id : 7, data : vec![1] };
+    write_raw_command(&mut first, MODULE_OUTPUT, &output.encode()).unwrap();
+
+    let mut stream = TcpStream::connect(em_address(&em)).unwrap();
+    stream.write_all(&first[..7]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let second = {
+        let em = em.clone();
+        thread::spawn(move || em.module_output(HANDLE_INPUT, 7, &[2]))
+    };
+    thread::sleep(Duration::from_millis(200));
+    stream.write_all(&first[7..]).unwrap();
+
+    assert!(second.join().unwrap().is_some());
+    let data : Vec<u8> = to.wait_calls(2, TIMEOUT).iter().map(|c| c.data[2]).collect();
+    assert_eq!(data, vec![1, 2]);
+}
+
+
+#[test]
+fn remote_output_ack() {
+    let em_a = TestEm::start();
+    let em_b = TestEm::start();
+    let _from = MockModule::start(&em_a, 1);
+    let to = MockModule::start(&em_b, 4);
+
+    em_a.client.add_connection(3, 4, em_address(&em_b), false).unwrap();
+
+    // the result of the module on EM B confirms the delivery
+    let result = em_a.module_output_ack(HANDLE_INPUT, 3, &[9, 8, 7, 6]);
+
+    assert_eq!(result, (0, vec![0, 3, 9, 8, 7, 6]));
+    assert_eq!(to.calls(), vec![Call { entry : HANDLE_INPUT, data : vec![0, 3, 9, 8, 7, 6] }]);
+}
+
+
+#[test]
+fn dead_letters() {
+    let em = TestEm::start();
+
+    let (code, _) = em.module_output_ack(HANDLE_INPUT, 7, &[1, 2, 3]);
+    assert_eq!(code, ResultCode::UnknownConnection as u8);
+    em.module_output(HANDLE_INPUT, 7, &[4, 5]);
+
+    let letters = em.client.dead_letters(true).unwrap();
+    assert_eq!(letters.undelivered, vec![(7, 2)]);
+    assert_eq!(letters.events.len(), 2);
+    assert_eq!(letters.events[0].conn_id, 7);
+    assert_eq!(letters.events[0].entry, HANDLE_INPUT);
+    assert_eq!(letters.events[0].code, ResultCode::UnknownConnection);
+
+    assert_eq!(em.client.dead_letters(false).unwrap(), DeadLetters::default());
+}
+
+
+#[test]
+fn remote_request() {
+    let em_a = TestEm::start();
+    let em_b = TestEm::start();
+    let _from = MockModule::start(&em_a, 1);
+    let to = MockModule::start(&em_b, 4);
+
+    em_a.client.add_connection(3, 4, em_address(&em_b), false).unwrap();
+
+    // the response of the module on EM B goes back to the module on EM A
+    let result = em_a.module_output(HANDLE_HANDLER, 3, &[9, 8, 7, 6]);
+
+    assert_eq!(result, Some((0, vec![0, 3, 9, 8, 7, 6])));
+    assert_eq!(to.calls(), vec![Call { entry : HANDLE_HANDLER, data : vec![0, 3, 9, 8, 7, 6] }]);
+}
+
+
+#[test]
+fn remote_output_from_client() {
+    let em = TestEm::start();
+    let to = MockModule::start(&em, 2);
+
+    em.client.remote_output(2, 5, &[1, 2, 3]).unwrap();
+    assert_eq!(em.client.remote_request(2, 5, &[4, 5, 6]).unwrap(), vec![0, 5, 4, 5, 6]);
+
+    let mut calls = to.wait_calls(2, TIMEOUT);
+    calls.sort_by_key(|c| c.entry);
+    assert_eq!(calls, vec![
+        Call { entry : HANDLE_INPUT, data : vec![0, 5, 1, 2, 3] },
+        Call { entry : HANDLE_HANDLER, data : vec![0, 5, 4, 5, 6] }
+    ]);
+}
+
+
+/// Send a Batch of `commands`, returns the code and the result of each one
+fn batch(em : &TestEm, atomic : bool, commands : &[(u8, Vec<u8>)]) -> (ResultCode, Vec<u8>) {
+    let mut payload = vec![atomic as u8];
+    for (code, data) in commands {
+        payload.push(*code);
+        payload.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        payload.extend_from_slice(data);
+    }
+
+    let results = match em.client.command(ExtCommandCode::Batch as u8, &payload) {
+        Ok(results)                             => (ResultCode::Ok, results),
+        Err(ClientError::Failed(code, results)) => (code, results),
+        Err(e)                                  => panic!("Batch failed: {}", e)
+    };
+
+    // results are: [<code (u8)><len (u16)><error>]
+    let mut codes = Vec::new();
+    let mut rest = &results.1[..];
+    while rest.len() >= 3 {
+        codes.push(rest[0]);
+        rest = &rest[3 + bytes_to_u16(&rest[1..3]) as usize..];
+    }
+
+    (results.0, codes)
+}
+
+
+#[test]
+fn batch_rollback() {
+    let em = TestEm::start();
+    let _from = MockModule::start(&em, 1);
+    let to = MockModule::start(&em, 2);
+
+    let add = AddConnection { conn_id : 7, to_sm : 2, local : true, em_port : em.port(),
+        em_address : Ipv4Addr::LOCALHOST }.encode();
+    let policy = SetConnectionPolicy { conn_id : 8, policy : DeliveryPolicy::RoundRobin }.encode();
+    let commands = vec![(ADD_CONNECTION, add), (ExtCommandCode::SetConnectionPolicy as u8, policy)];
+
+    // the second command fails, so the first one is rolled back
+    let unknown = ResultCode::UnknownConnection as u8;
+    assert_eq!(batch(&em, true, &commands), (ResultCode::GenericError, vec![0, unknown]));
+    assert_eq!(em.module_output_ack(HANDLE_INPUT, 7, &[1]).0, unknown);
+
+    // without the atomic flag, the commands that succeeded are kept
+    assert_eq!(batch(&em, false, &commands), (ResultCode::Ok, vec![0, unknown]));
+    assert_eq!(em.module_output_ack(HANDLE_INPUT, 7, &[1]).0, 0);
+    assert_eq!(to.calls().len(), 1);
+}
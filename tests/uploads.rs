@@ -0,0 +1,119 @@
+mod common;
+
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+
+use event_manager::client::ClientError;
+use event_manager::protocol::{ResultCode, bytes_to_u16};
+
+use common::*;
+
+
+fn failed<T : std::fmt::Debug>(res : Result<T, ClientError>) -> ResultCode {
+    match res {
+        Err(ClientError::Failed(code, _))   => code,
+        res                                 => panic!("Unexpected result: {:?}", res)
+    }
+}
+
+
+#[test]
+fn limits() {
+    let mut config = config();
+    config.max_uploads = 2;
+    config.max_upload_size = 100;
+    let em = TestEm::with_config(config);
+
+    assert_eq!(failed(em.upload_begin(101, 0)), ResultCode::PayloadTooLarge);
+
+    em.upload_begin(100, 0).unwrap();
+    let id = em.upload_begin(10, 0).unwrap();
+    assert_eq!(failed(em.upload_begin(10, 0)), ResultCode::BadRequest);
+
+    // a finished upload makes room for a new one
+    em.upload_data(id, 0, &[&[0; 10]], 1);
+    em.upload_finish(id).unwrap();
+    em.upload_begin(10, 0).unwrap();
+}
+
+
+#[test]
+fn expiry() {
+    let mut config = config();
+    config.upload_ttl = 0;
+    let em = TestEm::with_config(config);
+
+    let id = em.upload_begin(10, 0).unwrap();
+    assert_eq!(em.upload_status(id).unwrap(), 0);
+
+    // idle uploads are dropped when a new one starts
+    thread::sleep(Duration::from_millis(10));
+    em.upload_begin(10, 0).unwrap();
+    assert_eq!(failed(em.upload_status(id)), ResultCode::NotFound);
+}
+
+
+#[test]
+fn reset() {
+    let em = TestEm::start();
+
+    let id = em.upload_begin(10, 0).unwrap();
+    em.upload_data(id, 0, &[&[1; 4]], 1);
+
+    em.client.reset().unwrap();
+    assert_eq!(failed(em.upload_status(id)), ResultCode::NotFound);
+}
+
+
+#[test]
+fn resume() {
+    let em = TestEm::native();
+    let chunks : Vec<&[u8]> = SCRIPT.chunks(16).collect();
+    let id = em.upload_begin(SCRIPT.len() as u32, 0).unwrap();
+
+    // the connection is lost after the first chunk
+    assert_eq!(em.upload_data(id, 0, &chunks, 1), vec![16]);
+    assert_eq!(em.upload_status(id).unwrap(), 16);
+
+    em.upload_data(id, 16, &chunks[1..], chunks.len());
+    assert_eq!(em.upload_status(id).unwrap(), SCRIPT.len() as u32);
+    let hash = em.upload_finish(id).unwrap();
+    assert_eq!(failed(em.upload_status(id)), ResultCode::NotFound);
+
+    // the artifact is loaded by its hash
+    let (code, index) = em.load_module(&[(OPTION_ARTIFACT, &hash)], &[]);
+    assert_eq!(code, 0);
+    assert_eq!(em.wait_logs(bytes_to_u16(&index), 1).len(), 1);
+}
+
+
+#[test]
+fn incomplete() {
+    let em = TestEm::start();
+    let id = em.upload_begin(10, 0).unwrap();
+    em.upload_data(id, 0, &[&[1; 4]], 1);
+
+    assert_eq!(failed(em.upload_finish(id)), ResultCode::BadRequest);
+    assert_eq!(em.upload_status(id).unwrap(), 4);
+}
+
+
+#[test]
+fn compressed() {
+    let em = TestEm::start();
+    let hash = em.upload_artifact(SCRIPT).to_vec();
+
+    let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(SCRIPT).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let zstd = zstd::encode_all(SCRIPT, 0).unwrap();
+
+    for (compression, data) in [(1, gzip), (2, zstd)] {
+        let id = em.upload_begin(data.len() as u32, compression).unwrap();
+        em.upload_data(id, 0, &[&data], 1);
+        assert_eq!(em.upload_finish(id).unwrap(), hash);
+    }
+}